@@ -0,0 +1,293 @@
+use crate::fs::contained;
+use crate::import;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Adopt is the result of the `adopt` command.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Adopt {
+    name: String,
+    from: PathBuf,
+    to: PathBuf,
+    /// moved is false when the repo was already at its canonical path.
+    moved: bool,
+    /// added is false when the config already had the repo.
+    added: bool,
+    /// link is the symlink left at from, if any.
+    link: Option<PathBuf>,
+    /// worktrees are the linked worktrees that were repointed at the moved repo.
+    worktrees: Vec<PathBuf>,
+}
+
+impl Adopt {
+    pub fn added(&mut self, added: bool) -> &mut Self {
+        self.added = added;
+        self
+    }
+}
+
+impl fmt::Display for Adopt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.moved {
+            true => write!(f, "moved {} to {}", self.from.display(), self.to.display())?,
+            false => write!(f, "{} is already in place", self.to.display())?,
+        }
+        match self.added {
+            true => writeln!(f, " and added {}", self.name)?,
+            false => writeln!(f, "; {} was already managed", self.name)?,
+        }
+        if let Some(l) = &self.link {
+            writeln!(f, "linked {} to it", l.display())?;
+        }
+        for w in &self.worktrees {
+            writeln!(f, "repointed worktree {}", w.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// plan inspects the repo at from and returns it with its canonical path
+/// under root, refusing repos that can't be moved there.
+pub fn plan(root: &Path, from: &Path, pin: bool) -> Result<(import::Found, PathBuf)> {
+    let from = from
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {:?}", from))?;
+
+    // A linked worktree or submodule only has a .git file pointing elsewhere.
+    let git = from.join(".git");
+    if git.is_file() {
+        return Err(anyhow!(
+            "{:?} is a linked worktree or submodule; adopt the repo it belongs to",
+            from
+        ));
+    }
+    if !git.is_dir() {
+        return Err(anyhow!("{:?} isn't a git repo", from));
+    }
+
+    // from is canonical, so to has to be too for the two to be compared.
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("failed to resolve root {:?}", root))?;
+    let found = import::inspect(&from, pin)?;
+    let to = contained(&root, &root.join(found.repo().get_name()))?;
+    if to != from {
+        if to.exists() || to.is_symlink() {
+            return Err(anyhow!("{:?} already exists", to));
+        }
+        if to.starts_with(&from) || root.starts_with(&from) {
+            return Err(anyhow!("can't move {:?} into itself", from));
+        }
+    }
+    Ok((found, to))
+}
+
+/// adopt moves the repo at from to to, repointing its linked worktrees and,
+/// when link is true, leaving a symlink behind.
+pub fn adopt(found: &import::Found, to: &Path, link: bool) -> Result<Adopt> {
+    let from = found.path().to_path_buf();
+    let mut a = Adopt {
+        name: found.repo().get_name().to_owned(),
+        from: from.clone(),
+        to: to.to_path_buf(),
+        moved: false,
+        added: false,
+        link: None,
+        worktrees: Vec::new(),
+    };
+    if from == to {
+        return Ok(a);
+    }
+
+    info!("Moving {:?} to {:?}", from, to);
+    fs::create_dir_all(to.parent().unwrap_or(to))?;
+    move_dir(&from, to)?;
+    a.moved = true;
+
+    a.worktrees = repoint(&from, to).context("failed to repoint worktrees")?;
+
+    if link {
+        symlink(to, &from).with_context(|| format!("failed to link {:?}", from))?;
+        a.link = Some(from);
+    }
+    Ok(a)
+}
+
+/// undo moves an adopted repo back, e.g., when the config can't be written.
+pub fn undo(a: &Adopt) -> Result<()> {
+    if !a.moved {
+        return Ok(());
+    }
+    if a.link.is_some() {
+        fs::remove_file(&a.from)?;
+    }
+    move_dir(&a.to, &a.from)?;
+    repoint(&a.to, &a.from)?;
+    Ok(())
+}
+
+// move_dir renames from to to, falling back to copying and then removing from
+// when they're on different filesystems e.g., /tmp.
+pub fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            debug!("Copying {:?} to {:?} across filesystems", from, to);
+            let tmp = to.with_file_name(format!(
+                ".{}.gitrs-partial",
+                to.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            if let Err(e) = copy_dir(from, &tmp) {
+                let _ = fs::remove_dir_all(&tmp);
+                return Err(e);
+            }
+            fs::rename(&tmp, to)?;
+            fs::remove_dir_all(from)?;
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to move {:?} to {:?}", from, to)),
+    }
+}
+
+// copy_dir copies a directory tree, keeping symlinks as symlinks.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in WalkDir::new(from) {
+        let e = entry?;
+        let dst = to.join(e.path().strip_prefix(from)?);
+        let t = e.file_type();
+        if t.is_dir() {
+            fs::create_dir_all(&dst)?;
+        } else if t.is_symlink() {
+            symlink(fs::read_link(e.path())?, &dst)?;
+        } else {
+            fs::copy(e.path(), &dst)?;
+        }
+    }
+    Ok(())
+}
+
+// repoint updates the links between a moved repo and its linked worktrees,
+// which git records as absolute paths in both directions, returning the
+// worktrees it updated.
+pub fn repoint(from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+    let admin = to.join(".git/worktrees");
+    if !admin.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut worktrees = Vec::new();
+    for entry in fs::read_dir(&admin)? {
+        let dir = entry?.path();
+        let gitdir = dir.join("gitdir");
+        let Ok(text) = fs::read_to_string(&gitdir) else {
+            continue;
+        };
+
+        // A worktree inside the repo moved along with it.
+        let mut file = PathBuf::from(text.trim_end());
+        if let Ok(rel) = file.strip_prefix(from) {
+            file = to.join(rel);
+            fs::write(&gitdir, format!("{}\n", file.display()))?;
+        }
+
+        if file.is_file() {
+            fs::write(&file, format!("gitdir: {}\n", dir.display()))?;
+            worktrees.push(file.parent().unwrap_or(&file).to_path_buf());
+        }
+    }
+    worktrees.sort();
+    Ok(worktrees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Repository, Signature, WorktreeAddOptions};
+    use tempfile::tempdir;
+
+    fn init(p: &Path) -> Repository {
+        let repo = Repository::init(p).expect("failed to init");
+        repo.remote("origin", "git@github.com:a/a.git")
+            .expect("failed to add remote");
+        let sig = Signature::now("gitrs", "gitrs@localhost").unwrap();
+        let oid = repo.index().unwrap().write_tree().unwrap();
+        let tree = repo.find_tree(oid).unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+            .expect("failed to commit");
+        drop(tree);
+        repo
+    }
+
+    #[test]
+    fn test_adopt() {
+        let root = tempdir().expect("Failed to create tempdir");
+        let elsewhere = tempdir().expect("Failed to create tempdir");
+        let from = elsewhere.path().canonicalize().unwrap().join("a");
+
+        let repo = init(&from);
+        let wt = elsewhere.path().canonicalize().unwrap().join("a-wt");
+        repo.worktree("wt", &wt, Some(&WorktreeAddOptions::new()))
+            .expect("failed to add worktree");
+        drop(repo);
+
+        assert!(plan(root.path(), &wt, false).is_err());
+
+        let (found, to) = plan(root.path(), &from, false).expect("plan failed");
+        assert_eq!(
+            to,
+            root.path().canonicalize().unwrap().join("github.com/a/a")
+        );
+
+        let got = adopt(&found, &to, true).expect("adopt failed");
+        assert!(got.moved);
+        assert_eq!(got.worktrees, vec![wt.clone()]);
+        assert_eq!(fs::read_link(&from).unwrap(), to);
+
+        // Both the repo and its worktree still work.
+        let repo = Repository::open(&to).expect("failed to open");
+        assert!(repo.find_worktree("wt").unwrap().validate().is_ok());
+        let w = Repository::open(&wt).expect("failed to open worktree");
+        assert_eq!(
+            w.path().canonicalize().unwrap(),
+            to.join(".git/worktrees/wt")
+        );
+
+        // Undoing moves it back.
+        undo(&got).expect("undo failed");
+        assert!(from.join(".git").is_dir());
+        assert!(!to.exists());
+        let repo = Repository::open(&from).expect("failed to open");
+        assert!(repo.find_worktree("wt").unwrap().validate().is_ok());
+
+        let got = adopt(&found, &to, false).expect("adopt failed");
+        assert_eq!(got.link, None);
+
+        // The canonical path is taken now.
+        init(&from);
+        assert!(plan(root.path(), &from, false).is_err());
+
+        // Already in place.
+        let (found, same) = plan(root.path(), &to, false).expect("plan failed");
+        assert!(!adopt(&found, &same, false).expect("adopt failed").moved);
+
+        // Through a root that's a symlink, it's still in place.
+        let link = elsewhere.path().join("root");
+        symlink(root.path(), &link).unwrap();
+        let (_, same) = plan(&link, &to, false).expect("plan failed");
+        assert_eq!(same, to);
+
+        // A symlink at the canonical path isn't mistaken for the repo.
+        let other = elsewhere.path().canonicalize().unwrap().join("b");
+        init(&other);
+        fs::remove_dir_all(&to).unwrap();
+        symlink(&other, &to).unwrap();
+        assert!(plan(root.path(), &other, false).is_err());
+    }
+}
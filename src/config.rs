@@ -1,27 +1,201 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
 use log::{debug, info};
-use serde::{Deserialize, Serialize};
-use serde_yaml;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File, OpenOptions};
-use std::path::PathBuf;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self as stdfs, create_dir_all};
+use std::path::{Path, PathBuf};
 
+use crate::edit;
+use crate::fs;
+use crate::history;
+use crate::manifest;
+use crate::migrate;
 use crate::repo::Repo;
 
+// CONFIG_VERSION must be the last of migrate::VERSIONS.
 const CONFIG_VERSION: &str = "v1beta";
 
+/// CONFIG_FILE is the name of the config file in the root.
+pub const CONFIG_FILE: &str = ".gitrs.yaml";
+
+/// BACKUPS is the number of previous versions of the config file that are kept
+/// next to it as `<config>.bak.<n>`, where 1 is the most recent.
+pub const BACKUPS: usize = 5;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 struct Metadata {
     version: String,
+    /// root may use `~` and environment variables, which are expanded on read.
     root: PathBuf,
     #[serde(skip_serializing, skip_deserializing)]
+    expanded_root: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_sync: Option<String>,
+    /// repos_format is the shape gitrs writes `repos` in. When it isn't set,
+    /// the shape the file already uses is kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repos_format: Option<ReposFormat>,
+    /// history commits every change gitrs makes to the config file to a git
+    /// repo in the config file's directory.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    history: bool,
+    #[serde(skip_serializing, skip_deserializing)]
+    path: PathBuf,
+}
+
+/// ReposFormat is the shape of `repos` in the config file.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReposFormat {
+    /// map is keyed by name e.g., `github.com/org/repo: {name: ..., url: ...}`.
+    #[default]
+    Map,
+    /// list is a list of entries e.g., `- name: github.com/org/repo`.
+    List,
+}
+
+/// Change describes the effect a mutating command had on the config.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Change {
+    action: Action,
+    name: String,
+    /// changed is false when the config already reflected the request e.g.,
+    /// removing a repo that isn't managed.
+    changed: bool,
+    repo: Option<Repo>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Add,
+    Remove,
+    Tag,
+    Untag,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.action, self.changed) {
+            (Action::Add, true) => write!(f, "added {}", self.name),
+            (Action::Add, false) => write!(f, "updated {}", self.name),
+            (Action::Remove, true) => write!(f, "removed {}", self.name),
+            (Action::Remove, false) => write!(f, "{} is not managed by gitrs", self.name),
+            (Action::Tag, true) => write!(f, "tagged {}", self.name),
+            (Action::Tag, false) => write!(f, "{} already has those tags", self.name),
+            (Action::Untag, true) => write!(f, "untagged {}", self.name),
+            (Action::Untag, false) => write!(f, "{} doesn't have those tags", self.name),
+        }
+    }
+}
+
+/// Changes are the results of adding or removing several repos at once.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Changes {
+    changes: Vec<Change>,
+}
+
+impl Changes {
+    /// changed returns the names of the repos whose change did something.
+    pub fn changed(&self) -> Vec<String> {
+        self.changes
+            .iter()
+            .filter(|c| c.changed)
+            .map(|c| c.name.clone())
+            .collect()
+    }
+}
+
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in &self.changes {
+            writeln!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+// dedup drops repeated names, keeping the first.
+fn dedup(names: &[String]) -> Vec<String> {
+    let mut seen = Vec::new();
+    for n in names {
+        if !seen.contains(n) {
+            seen.push(n.clone());
+        }
+    }
+    seen
+}
+
+// message is the history message for adding or removing repos.
+fn message(verb: &str, changes: &[Change]) -> String {
+    let changed: Vec<&Change> = changes.iter().filter(|c| c.changed).collect();
+    match changed[..] {
+        [c] => format!("{} {}", verb, c.name),
+        _ => format!("{} {} repos", verb, changed.len()),
+    }
+}
+
+/// Restore describes a config file restored from a backup.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Restore {
     path: PathBuf,
+    backup: PathBuf,
+    repos: usize,
+}
+
+impl fmt::Display for Restore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "restored {} ({} repos) from {}",
+            self.path.display(),
+            self.repos,
+            self.backup.display()
+        )
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Config {
     metadata: Metadata,
-    repos: HashMap<String, Repo>,
+    /// include lists other manifests whose repos are merged under this
+    /// config's own. Later includes take precedence over earlier ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    /// exclude lists names or globs of included repos to drop.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude: Vec<String>,
+    /// own is the config's own repos. See merged for the repos in effect.
+    #[serde(
+        rename = "repos",
+        serialize_with = "sorted",
+        deserialize_with = "map_or_list"
+    )]
+    own: HashMap<String, Repo>,
+    /// unknown holds top-level keys gitrs doesn't recognize e.g., a typo'd
+    /// `repos` key. They're kept so that writes don't drop them.
+    #[serde(flatten, skip_serializing_if = "HashMap::is_empty")]
+    unknown: HashMap<String, serde_yaml::Value>,
+    /// format is the shape of `repos` in the file that was read.
+    #[serde(skip)]
+    format: ReposFormat,
+    /// declared is repos as written in the file, so that included repos can
+    /// be overridden field by field.
+    #[serde(skip)]
+    declared: manifest::Layer,
+    /// inherited is the repos from included manifests.
+    #[serde(skip)]
+    inherited: manifest::Layer,
+    /// merged is the repos in effect: inherited with the config's own repos
+    /// merged over them.
+    #[serde(skip)]
+    merged: HashMap<String, Repo>,
+    /// sources is the manifest each repo in merged was first declared in.
+    #[serde(skip)]
+    sources: HashMap<String, String>,
 }
 
 impl Config {
@@ -31,10 +205,22 @@ impl Config {
         let cfg = Config {
             metadata: Metadata {
                 version: CONFIG_VERSION.to_owned(),
-                root: r,
+                root: r.clone(),
+                expanded_root: r,
+                last_sync: None,
+                repos_format: None,
+                history: false,
                 path: p,
             },
-            repos: HashMap::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            own: HashMap::new(),
+            unknown: HashMap::new(),
+            format: ReposFormat::Map,
+            declared: manifest::Layer::default(),
+            inherited: manifest::Layer::default(),
+            merged: HashMap::new(),
+            sources: HashMap::new(),
         };
 
         Ok(cfg)
@@ -42,12 +228,11 @@ impl Config {
 
     /// create creates the config file.
     pub fn create(&mut self) -> Result<Config> {
-        create_dir_all(self.metadata.root.as_path()).context("Failed to create dir")?;
+        create_dir_all(self.metadata.expanded_root.as_path()).context("Failed to create dir")?;
 
         if !self.metadata.path.exists() {
             debug!("Creating config {:?}", self.path());
-            File::create(self.metadata.path.as_path()).context("Failed to create")?;
-            self.write().context("Failed to write")?
+            self.write("create config").context("Failed to write")?
         }
 
         debug!("Reading config {:?}", self.path());
@@ -55,72 +240,824 @@ impl Config {
     }
 
     /// write writes the config file.
-    fn write(&self) -> Result<()> {
+    ///
+    /// Changes to repos are made as targeted edits to the existing file, so
+    /// that comments, blank lines and ordering are preserved. If the file
+    /// can't be edited that way e.g., it doesn't exist yet, the whole config
+    /// is rewritten with repos sorted by name, in the format from
+    /// `metadata.repos_format`.
+    ///
+    /// message describes the change for the config's history.
+    fn write(&self, message: &str) -> Result<()> {
         info!("Writing to config file: {:?}", self.metadata.path.as_path()); // path gets moved
-        let f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(self.metadata.path.as_path())
-            .context("Couldn't open file")?;
-        Ok(serde_yaml::to_writer(f, &self)?)
+        let contents = match self.edit() {
+            Ok(s) => s,
+            Err(e) => {
+                debug!("Rewriting config instead of editing it: {:#}", e);
+                self.to_yaml()?
+            }
+        };
+        self.write_bytes(contents.as_bytes(), message)
+    }
+
+    // edit applies the difference between the repos in the config file and
+    // the repos in self as targeted edits to the config file's text.
+    fn edit(&self) -> Result<String> {
+        let text = stdfs::read_to_string(self.metadata.path.as_path())?;
+        let mut on_disk = parse(&text)?;
+        on_disk.metadata.path = self.metadata.path.clone();
+        on_disk.metadata.expanded_root = self.metadata.expanded_root.clone();
+
+        if on_disk.metadata != self.metadata
+            || on_disk.include != self.include
+            || !self.exclude.starts_with(&on_disk.exclude)
+            || on_disk.unknown != self.unknown
+        {
+            return Err(anyhow!("more than repos changed"));
+        }
+        if on_disk.format != self.write_format() {
+            return Err(anyhow!("repos format changed"));
+        }
+
+        let mut doc = edit::Document::parse(&text);
+
+        // Forgetting an inherited repo appends to exclude.
+        for p in &self.exclude[on_disk.exclude.len()..] {
+            doc.exclude(p.as_str())?;
+        }
+
+        let mut removed: Vec<&String> = on_disk
+            .own
+            .keys()
+            .filter(|k| !self.own.contains_key(*k))
+            .collect();
+        removed.sort();
+        for k in removed {
+            doc.remove(k)?;
+        }
+
+        let mut keys: Vec<&String> = self.own.keys().collect();
+        keys.sort();
+        for k in keys {
+            let r = &self.own[k];
+            if on_disk.own.get(k) != Some(r) {
+                doc.upsert(k, &serde_yaml::to_value(r)?)?;
+            }
+        }
+
+        // Never write an edit that doesn't say what self says.
+        let out = doc.to_string();
+        let check = parse(&out)?;
+        if check.own != self.own || check.exclude != self.exclude || check.unknown != self.unknown {
+            return Err(anyhow!("edited config doesn't match"));
+        }
+
+        Ok(out)
+    }
+
+    // write_bytes backs up the config file and then atomically replaces it, so
+    // a crash mid-write leaves either the old or the new config, never a mix.
+    //
+    // With history enabled, edits made since gitrs last wrote the file are
+    // committed first, so that the commit for message holds only this change.
+    fn write_bytes(&self, contents: &[u8], message: &str) -> Result<()> {
+        let p = self.metadata.path.as_path();
+        if self.metadata.history && p.exists() {
+            history::commit(p, history::OUTSIDE).context("Failed to record config history")?;
+        }
+
+        self.backup().context("Failed to back up config")?;
+        fs::write_atomic(p, contents)?;
+
+        if self.metadata.history {
+            history::commit(p, message).context("Failed to record config history")?;
+        }
+        Ok(())
+    }
+
+    // backup rotates the existing backups and copies the config file to the
+    // most recent one, dropping the oldest.
+    fn backup(&self) -> Result<()> {
+        let p = self.metadata.path.as_path();
+        if !p.exists() || stdfs::metadata(p)?.len() == 0 {
+            return Ok(());
+        }
+
+        for n in (1..BACKUPS).rev() {
+            let from = backup_path(p, n);
+            if from.exists() {
+                stdfs::rename(from, backup_path(p, n + 1))?;
+            }
+        }
+
+        stdfs::copy(p, backup_path(p, 1))?;
+        Ok(())
+    }
+
+    /// restore replaces the config file with backup n, where 1 is the most
+    /// recent. The config being replaced is itself backed up first, so a
+    /// restore can be undone by restoring 1.
+    pub fn restore(&self, n: usize) -> Result<Restore> {
+        let b = backup_path(self.metadata.path.as_path(), n);
+        if !b.exists() {
+            return Err(anyhow!("backup {:?} doesn't exist", b));
+        }
+
+        // Refuse to restore a backup that isn't a valid config.
+        let mut restored = self
+            .read(b.clone())
+            .with_context(|| format!("invalid backup {:?}", b))?;
+
+        // self may not have been read, since the config being replaced may not
+        // parse, so whether to record history also comes from the backup.
+        restored.metadata.path = self.metadata.path.clone();
+        restored.metadata.history |= self.metadata.history;
+
+        info!("Restoring config file {:?} from {:?}", self.path(), b);
+        restored.write_bytes(
+            &stdfs::read(&b)?,
+            &format!("restore config from backup {}", n),
+        )?;
+
+        Ok(Restore {
+            path: self.path(),
+            backup: b,
+            repos: self.read(self.path())?.repos().len(),
+        })
+    }
+
+    /// history lists the changes recorded in the config's history, most
+    /// recent first.
+    pub fn history(&self, limit: Option<usize>) -> Result<history::History> {
+        self.history_enabled()?;
+        history::history(self.metadata.path.as_path(), limit)
+    }
+
+    /// undo reverts the most recent change in the config's history, recording
+    /// the revert as a change of its own, so that undoing twice redoes.
+    pub fn undo(&self) -> Result<history::Undo> {
+        self.history_enabled()?;
+        let p = self.metadata.path.as_path();
+
+        history::commit(p, history::OUTSIDE)?;
+        let (change, before) = history::last(p)?;
+        let before = before.ok_or_else(|| anyhow!("can't undo the config's creation"))?;
+
+        // Refuse to revert to a version that isn't a valid config.
+        parse(std::str::from_utf8(&before)?)
+            .and_then(|c| c.validate())
+            .context("invalid config before the change")?;
+
+        info!("Undoing config change {:?}", change);
+        self.write_bytes(&before, &history::Undo::message(&change))?;
+
+        Ok(history::Undo::new(change))
+    }
+
+    fn history_enabled(&self) -> Result<()> {
+        match self.metadata.history {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "config history isn't enabled, set metadata.history to true"
+            )),
+        }
     }
 
     /// read reads the config file.
+    ///
+    /// Configs written with an older schema version are migrated to the
+    /// current one. When p is this config's path, the original file is backed
+    /// up to `<config>.<version>.bak` and the migrated config is written.
     pub fn read(&self, p: PathBuf) -> Result<Config> {
-        let f = File::open(p.clone())?;
+        self.read_with(p, |k| std::env::var(k).ok())
+    }
+
+    // read_with is read with var in place of the environment when expanding
+    // the root and locating the manifest cache.
+    fn read_with(&self, p: PathBuf, var: impl Fn(&str) -> Option<String>) -> Result<Config> {
+        let text = stdfs::read_to_string(p.as_path())?;
+
+        let mut v: serde_yaml::Value = serde_yaml::from_str(&text)?;
+        let from = migrate::migrate(&mut v)?;
+
+        // Parse the text itself when possible, so errors have locations.
+        let mut cfg = match from {
+            Some(_) => {
+                let format = format_of(&v);
+                let mut cfg: Config = serde_yaml::from_value(v.clone())?;
+                cfg.format = format;
+                cfg
+            }
+            None => parse(&text)?,
+        };
+        cfg.metadata.path = p.clone();
+        cfg.metadata.expanded_root = fs::expand_with(&cfg.metadata.root, &var)?;
+        cfg.fill()?;
+        cfg.declared = manifest::Layer::declared(&v, &p.display().to_string())?;
+        cfg.inherited = manifest::inherited_with(&v, &p, &var)?;
+        cfg.merge()?;
+        cfg.validate()?;
+
+        if let Some(from) = from {
+            info!(
+                "Migrated config {:?} from {} to {}",
+                p, from, cfg.metadata.version
+            );
+
+            if p == self.metadata.path {
+                let b = migration_backup_path(p.as_path(), &from);
+                stdfs::write(&b, &text)
+                    .with_context(|| format!("Failed to back up config to {:?}", b))?;
+                cfg.write_bytes(
+                    cfg.to_yaml()?.as_bytes(),
+                    &format!("migrate config from {} to {}", from, cfg.metadata.version),
+                )?;
+            }
+        }
 
-        let mut cfg: Config = serde_yaml::from_reader(f)?;
-        cfg.metadata.path = p;
         Ok(cfg)
     }
 
+    /// fetch_manifests fetches the git manifests the config includes, so that
+    /// the next read sees their repos as of now.
+    pub fn fetch_manifests(&self) -> Result<()> {
+        let text = stdfs::read_to_string(self.metadata.path.as_path())?;
+        manifest::fetch(&serde_yaml::from_str(&text)?, self.metadata.path.as_path())
+    }
+
+    /// validate checks that every repo in the config, including those it
+    /// includes, has a valid name.
+    ///
+    /// Both the key and the name are checked because sync uses them as paths
+    /// relative to the root.
+    pub fn validate(&self) -> Result<()> {
+        for (k, r) in self.own.iter().chain(self.merged.iter()) {
+            for n in [k.as_str(), r.get_name()] {
+                Repo::new()
+                    .name(n.to_owned())
+                    .with_context(|| format!("invalid repo: {:?}", n))?;
+            }
+        }
+        Ok(())
+    }
+
+    // fill derives the fields a repo entry may omit.
+    fn fill(&mut self) -> Result<()> {
+        for (k, r) in self.own.iter_mut() {
+            r.fill(k)
+                .with_context(|| format!("invalid repo: {:?}", k))?;
+        }
+        Ok(())
+    }
+
+    // merge recomputes the repos in effect from the inherited repos and the
+    // config's own.
+    fn merge(&mut self) -> Result<()> {
+        let mut layer = self.inherited.clone();
+        layer.overlay(self.declared.clone());
+
+        self.merged.clear();
+        self.sources.clear();
+        for (name, e) in layer.entries() {
+            let mut r: Repo = serde_yaml::from_value(serde_yaml::Value::Mapping(e.fields.clone()))
+                .with_context(|| format!("invalid repo: {:?}", name))?;
+            r.fill(name)
+                .with_context(|| format!("invalid repo: {:?}", name))?;
+            self.merged.insert(name.clone(), r);
+            self.sources.insert(name.clone(), e.source.clone());
+        }
+        Ok(())
+    }
+
+    // write_format is the shape the config file's repos should be written in.
+    fn write_format(&self) -> ReposFormat {
+        self.metadata.repos_format.unwrap_or(self.format)
+    }
+
+    // to_yaml renders the whole config, with repos sorted by name.
+    fn to_yaml(&self) -> Result<String> {
+        let mut v = serde_yaml::to_value(self)?;
+        if self.write_format() == ReposFormat::List {
+            let sorted: BTreeMap<_, _> = self.own.iter().collect();
+            v["repos"] = serde_yaml::to_value(sorted.values().collect::<Vec<_>>())?;
+        }
+        Ok(serde_yaml::to_string(&v)?)
+    }
+
+    /// sanity_check reports problems that suggest the config doesn't say what
+    /// its author meant it to, even though it parsed e.g., unknown top-level
+    /// keys.
+    pub fn sanity_check(&self) -> Result<()> {
+        if !self.unknown.is_empty() {
+            let mut keys: Vec<&String> = self.unknown.keys().collect();
+            keys.sort();
+            return Err(anyhow!("unknown top-level keys: {:?}", keys));
+        }
+        Ok(())
+    }
+
     /// add adds a repo to the config and indicates whether or not the repo
-    /// should be pinned at the first fetched commit sha.
+    /// should be pinned at the first fetched commit sha. tags are added to
+    /// any the repo already has, and a repo that's already pinned stays so.
     ///
     /// Pinning will prevent future fs::sync calls from checking for updates.
     /// (This statement is a bit of package bleed, consider removing).
-    pub fn add(&mut self, repo: String, pin: bool) -> Result<()> {
-        let mut binding = Repo::new();
-        let r = binding.name(repo.clone())?.pin(pin);
+    pub fn add(&mut self, repo: String, pin: bool, tags: &[String]) -> Result<Change> {
+        let mut changes = self.add_all(&[repo], pin, tags)?;
+        Ok(changes.changes.remove(0))
+    }
 
-        debug!("Adding repo: {}", repo);
+    /// add_all adds repos in a single write. Every name is checked first, so
+    /// one invalid name leaves the config untouched.
+    pub fn add_all(&mut self, repos: &[String], pin: bool, tags: &[String]) -> Result<Changes> {
+        let mut want = Vec::new();
+        let mut invalid = Vec::new();
+        for name in dedup(repos) {
+            // Adding a repo that's already managed only changes what was asked
+            // for, so its pin, sha and branch survive.
+            let existing = self.merged.get(&name);
+            let mut r = existing.cloned().unwrap_or_default();
+            let mut all = r.get_tags().to_vec();
+            all.extend_from_slice(tags);
+            let res = match existing {
+                Some(_) => r.tags(all).map(|_| ()),
+                None => r.name(name.clone()).and_then(|r| r.tags(all)).map(|_| ()),
+            };
+            if let Err(e) = res {
+                invalid.push(format!("{}: {:#}", name, e));
+                continue;
+            }
+            if pin {
+                r.pin(true);
+            }
+            let changed = existing != Some(&r);
+            want.push((name, r, changed));
+        }
+        if !invalid.is_empty() {
+            return Err(anyhow!("invalid repos:\n{}", invalid.join("\n")));
+        }
+
+        let mut changes = Vec::new();
+        for (name, r, changed) in want {
+            debug!("Adding repo: {}", name);
+            if changed {
+                self.insert(&name, &r)?;
+            }
+            changes.push(Change {
+                action: Action::Add,
+                name,
+                changed,
+                repo: Some(r),
+            });
+        }
+        self.merge()?;
+        // A change that does nothing isn't written, so it neither rotates a
+        // backup out nor records a commit in the history.
+        if changes.iter().any(|c| c.changed) {
+            self.write(&message("add", &changes))?;
+        }
+
+        Ok(Changes { changes })
+    }
 
-        self.repos.insert(repo, r.to_owned());
-        self.write()
+    /// tag adds tags to a repo. Tagging an inherited repo copies it into the
+    /// config's own repos, since only the config's own file is ever written.
+    pub fn tag(&mut self, repo: String, tags: &[String]) -> Result<Change> {
+        self.retag(repo, tags, Action::Tag)
     }
 
-    /// remove removes a repo from the config.
+    /// untag removes tags from a repo.
+    pub fn untag(&mut self, repo: String, tags: &[String]) -> Result<Change> {
+        self.retag(repo, tags, Action::Untag)
+    }
+
+    fn retag(&mut self, repo: String, tags: &[String], action: Action) -> Result<Change> {
+        let mut r = self
+            .merged
+            .get(&repo)
+            .cloned()
+            .ok_or_else(|| anyhow!("{} is not managed by gitrs", repo))?;
+
+        let mut want = r.get_tags().to_vec();
+        match action {
+            Action::Untag => want.retain(|t| !tags.contains(t)),
+            _ => want.extend_from_slice(tags),
+        }
+        let before = r.get_tags().to_vec();
+        r.tags(want)?;
+        let changed = r.get_tags() != before;
+
+        if changed {
+            debug!("Tagging repo {} with {:?}", repo, r.get_tags());
+            self.declare(&repo, &r)?;
+            let verb = match action {
+                Action::Untag => "untag",
+                _ => "tag",
+            };
+            self.write(&format!("{} {} {}", verb, repo, tags.join(",")))?;
+        }
+
+        Ok(Change {
+            action,
+            name: repo,
+            changed,
+            repo: Some(r),
+        })
+    }
+
+    /// import adds the repos the config doesn't already have in a single
+    /// write, returning the names of those it added.
+    pub fn import(&mut self, repos: &[Repo]) -> Result<Vec<String>> {
+        let mut added = Vec::new();
+        for r in repos {
+            let name = r.get_name();
+            if self.merged.contains_key(name) || added.iter().any(|a| a == name) {
+                continue;
+            }
+            self.insert(name, r)?;
+            added.push(name.to_owned());
+        }
+
+        if !added.is_empty() {
+            debug!("Importing repos: {:?}", added);
+            self.merge()?;
+            let message = match added.as_slice() {
+                [name] => format!("import {}", name),
+                _ => format!("import {} repos", added.len()),
+            };
+            self.write(&message)?;
+        }
+        Ok(added)
+    }
+
+    // declare sets a repo in the config's own repos and recomputes the repos
+    // in effect.
+    fn declare(&mut self, name: &str, r: &Repo) -> Result<()> {
+        self.insert(name, r)?;
+        self.merge()
+    }
+
+    fn insert(&mut self, name: &str, r: &Repo) -> Result<()> {
+        self.own.insert(name.to_owned(), r.clone());
+        if let serde_yaml::Value::Mapping(m) = serde_yaml::to_value(r)? {
+            self.declared
+                .insert(name, m, &self.metadata.path.display().to_string());
+        }
+        Ok(())
+    }
+
+    /// remove removes a repo from the config. A repo inherited from an
+    /// included manifest is added to the config's exclude list instead, since
+    /// only the config's own file is ever written.
     ///
     /// Removing a repo from the config will indicate future fs::sync calls
     /// to ensure the repo directory is removed from the GITRS_ROOT directory.
     /// (This statement is a bit of package bleed, consider removing).
-    pub fn remove(&mut self, repo: String) -> Result<()> {
-        debug!("Removing repo: {}", repo);
+    pub fn remove(&mut self, repo: String) -> Result<Change> {
+        let mut changes = self.remove_all(&[repo])?;
+        Ok(changes.changes.remove(0))
+    }
+
+    /// remove_all removes repos in a single write.
+    pub fn remove_all(&mut self, repos: &[String]) -> Result<Changes> {
+        let mut changes = Vec::new();
+        for name in dedup(repos) {
+            debug!("Removing repo: {}", name);
 
-        self.repos.remove(&repo);
-        self.write()
+            let r = self.forget(&name)?;
+            changes.push(Change {
+                action: Action::Remove,
+                name,
+                changed: r.is_some(),
+                repo: r,
+            });
+        }
+        self.merge()?;
+        // A change that does nothing isn't written, so it neither rotates a
+        // backup out nor records a commit in the history.
+        if changes.iter().any(|c| c.changed) {
+            self.write(&message("remove", &changes))?;
+        }
+
+        Ok(Changes { changes })
+    }
+
+    // forget drops a repo from the config's own repos, excluding it when it's
+    // inherited, and returns it. The repos in effect aren't recomputed.
+    fn forget(&mut self, name: &str) -> Result<Option<Repo>> {
+        let r = self.merged.get(name).cloned();
+        self.own.remove(name);
+        self.declared.remove(name);
+        if self.inherited.contains(name) {
+            let p = Pattern::escape(name);
+            self.inherited.exclude(&[Pattern::new(&p)?]);
+            self.exclude.push(p);
+        }
+        Ok(r)
+    }
+
+    /// rename renames a repo, keeping its pin, branch and tags, and returns
+    /// it under its new name. Renaming an inherited repo excludes it and
+    /// declares it under the new name in the config's own repos.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<Repo> {
+        let mut r = self
+            .merged
+            .get(from)
+            .cloned()
+            .ok_or_else(|| anyhow!("{} is not managed by gitrs", from))?;
+        if self.merged.contains_key(to) {
+            return Err(anyhow!("{} is already managed by gitrs", to));
+        }
+        r.rename(to.to_owned())?;
+
+        debug!("Renaming repo {} to {}", from, to);
+        self.forget(from)?;
+        self.declare(to, &r)?;
+        self.write(&format!("mv {} {}", from, to))?;
+        Ok(r)
     }
 
     // Naming conventions https://rust-lang.github.io/api-guidelines/naming.html#getter-names-follow-rust-convention-c-getter
 
     pub fn root(&self) -> PathBuf {
-        self.metadata.root.to_path_buf()
+        self.metadata.expanded_root.to_path_buf()
     }
 
     pub fn path(&self) -> PathBuf {
         self.metadata.path.to_path_buf()
     }
 
+    pub fn backup_path(&self, n: usize) -> PathBuf {
+        backup_path(self.metadata.path.as_path(), n)
+    }
+
+    /// repos returns the repos in effect, including inherited ones.
     pub fn repos(&self) -> &HashMap<String, Repo> {
-        &self.repos
+        &self.merged
+    }
+
+    /// sources returns the manifest each repo was first declared in.
+    pub fn sources(&self) -> &HashMap<String, String> {
+        &self.sources
+    }
+}
+
+// parse parses a config from text and derives the fields its repos omit.
+// Unlike read, it doesn't validate or migrate.
+fn parse(text: &str) -> Result<Config> {
+    let mut cfg: Config = serde_yaml::from_str(text)?;
+    cfg.format = format_of(&serde_yaml::from_str(text)?);
+    for (k, r) in cfg.own.iter_mut() {
+        // Invalid names are left for validate to report.
+        let _ = r.fill(k);
+    }
+    Ok(cfg)
+}
+
+fn format_of(v: &serde_yaml::Value) -> ReposFormat {
+    match v.get("repos").map(|r| r.is_sequence()) {
+        Some(true) => ReposFormat::List,
+        _ => ReposFormat::Map,
+    }
+}
+
+// map_or_list deserializes repos from either a mapping keyed by name or a list
+// of entries, which must have unique names.
+fn map_or_list<'de, D: Deserializer<'de>>(d: D) -> Result<HashMap<String, Repo>, D::Error> {
+    struct Repos;
+
+    impl<'de> Visitor<'de> for Repos {
+        type Value = HashMap<String, Repo>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a mapping of repo names to repos or a list of repos")
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(HashMap::new())
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut m: A) -> Result<Self::Value, A::Error> {
+            let mut repos = HashMap::new();
+            while let Some((k, r)) = m.next_entry::<String, Repo>()? {
+                if repos.insert(k.clone(), r).is_some() {
+                    return Err(de::Error::custom(format!("duplicate repo {:?}", k)));
+                }
+            }
+            Ok(repos)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut s: A) -> Result<Self::Value, A::Error> {
+            let mut repos = HashMap::new();
+            let mut i = 0;
+            while let Some(r) = s.next_element::<Repo>()? {
+                let name = r.get_name().to_owned();
+                if name.is_empty() {
+                    return Err(de::Error::custom(format!(
+                        "repos[{}] doesn't have a name",
+                        i
+                    )));
+                }
+                if repos.insert(name.clone(), r).is_some() {
+                    return Err(de::Error::custom(format!("duplicate repo {:?}", name)));
+                }
+                i += 1;
+            }
+            Ok(repos)
+        }
+    }
+
+    d.deserialize_any(Repos)
+}
+
+// sorted serializes repos sorted by name, so that rewriting the config
+// doesn't shuffle it.
+fn sorted<S: Serializer>(repos: &HashMap<String, Repo>, s: S) -> Result<S::Ok, S::Error> {
+    repos.iter().collect::<BTreeMap<_, _>>().serialize(s)
+}
+
+/// Location is the config file in effect and where it was found.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Location {
+    path: PathBuf,
+    source: Source,
+    exists: bool,
+}
+
+/// Source is where the config file's location came from, in order of
+/// precedence.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// flag is the `--config` argument.
+    Flag,
+    /// env is the `GITRS_CONFIG` environment variable.
+    Env,
+    /// xdg is `$XDG_CONFIG_HOME/gitrs/config.yaml`, if it exists.
+    Xdg,
+    /// root is the config file in the root.
+    Root,
+}
+
+impl Location {
+    pub fn path(&self) -> PathBuf {
+        self.path.to_path_buf()
     }
 
-    pub fn repos_mut(&mut self) -> &mut HashMap<String, Repo> {
-        &mut self.repos
+    /// root_local reports whether the config file is the one in the root,
+    /// rather than one that says where the root is.
+    pub fn root_local(&self) -> bool {
+        self.source == Source::Root
     }
+
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only the path, so that e.g., `$EDITOR $(gitrs config path)` works.
+        write!(f, "{}", self.path.display())
+    }
+}
+
+/// locate finds the config file in effect: the `--config` argument, then
+/// `GITRS_CONFIG`, then `$XDG_CONFIG_HOME/gitrs/config.yaml` if it exists and
+/// finally the config file in root. Paths may use `~` and environment
+/// variables.
+pub fn locate(flag: Option<PathBuf>, root: &Path) -> Result<Location> {
+    locate_with(flag, root, |k| std::env::var(k).ok())
+}
+
+fn locate_with(
+    flag: Option<PathBuf>,
+    root: &Path,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Location> {
+    let xdg = match var("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(x) => fs::expand_with(Path::new(&x), &var)?,
+        None => fs::expand_with(Path::new("~/.config"), &var)?,
+    }
+    .join("gitrs/config.yaml");
+
+    let (path, source) = match (flag, var("GITRS_CONFIG").filter(|v| !v.is_empty())) {
+        (Some(p), _) => (fs::expand_with(&p, &var)?, Source::Flag),
+        (None, Some(p)) => (fs::expand_with(Path::new(&p), &var)?, Source::Env),
+        (None, None) if xdg.exists() => (xdg, Source::Xdg),
+        (None, None) => (root.join(CONFIG_FILE), Source::Root),
+    };
+
+    // Relative paths are relative to where gitrs was run, not to the root.
+    let path = std::path::absolute(&path)
+        .with_context(|| format!("failed to resolve config path {:?}", path))?;
+
+    Ok(Location {
+        exists: path.exists(),
+        path,
+        source,
+    })
+}
+
+/// root_of returns the expanded `metadata.root` of the config file at p.
+pub fn root_of(p: &Path) -> Result<PathBuf> {
+    root_of_with(p, |k| std::env::var(k).ok())
+}
+
+fn root_of_with(p: &Path, var: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    let text = stdfs::read_to_string(p).with_context(|| format!("failed to read {:?}", p))?;
+    let v: serde_yaml::Value = serde_yaml::from_str(&text)?;
+    let root = v
+        .get("metadata")
+        .and_then(|m| m.get("root"))
+        .and_then(|r| r.as_str())
+        .ok_or_else(|| anyhow!("config {:?} doesn't have a metadata.root", p))?;
+    fs::expand_with(Path::new(root), var)
+}
+
+/// Migration describes whether the config file is at the current schema
+/// version.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Migration {
+    path: PathBuf,
+    version: String,
+    current: String,
+    /// needed is true when the config file is at an older version.
+    needed: bool,
+    /// migrated is true when the config file was migrated.
+    migrated: bool,
+}
+
+impl Migration {
+    pub fn needed(&self) -> bool {
+        self.needed && !self.migrated
+    }
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.needed, self.migrated) {
+            (false, _) => write!(
+                f,
+                "{} is at the current version {}",
+                self.path.display(),
+                self.current
+            ),
+            (true, false) => write!(
+                f,
+                "{} needs migrated from {} to {}",
+                self.path.display(),
+                self.version,
+                self.current
+            ),
+            (true, true) => write!(
+                f,
+                "migrated {} from {} to {}",
+                self.path.display(),
+                self.version,
+                self.current
+            ),
+        }
+    }
+}
+
+/// migration checks whether the config file at p needs migrated and, unless
+/// check is set, migrates it.
+pub fn migration(root: PathBuf, p: PathBuf, check: bool) -> Result<Migration> {
+    let text =
+        stdfs::read_to_string(p.as_path()).with_context(|| format!("failed to read {:?}", p))?;
+    let v: serde_yaml::Value = serde_yaml::from_str(&text)?;
+    let needed = migrate::needed(&v)?;
+
+    let m = Migration {
+        path: p.clone(),
+        version: migrate::version(&v)?.to_owned(),
+        current: migrate::current().to_owned(),
+        needed,
+        migrated: needed && !check,
+    };
+
+    if m.migrated {
+        Config::new(root, p.clone())?.read(p)?;
+    }
+
+    Ok(m)
+}
+
+fn migration_backup_path(p: &Path, version: &str) -> PathBuf {
+    let mut s = p.as_os_str().to_owned();
+    s.push(format!(".{}.bak", version));
+    PathBuf::from(s)
+}
+
+fn backup_path(p: &Path, n: usize) -> PathBuf {
+    let mut s = p.as_os_str().to_owned();
+    s.push(format!(".bak.{}", n));
+    PathBuf::from(s)
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     use crate::repo;
@@ -175,7 +1112,7 @@ mod tests {
 
         assert_eq!(got.metadata.root, PathBuf::from("/foo"));
         assert_eq!(got.metadata.path, PathBuf::from("/foo/test.yaml"));
-        assert_eq!(got.repos.len(), 0);
+        assert_eq!(got.own.len(), 0);
         assert_eq!(got.metadata.version, String::from(CONFIG_VERSION));
     }
 
@@ -199,7 +1136,7 @@ mod tests {
         let root = setup();
         let mut first = create_test_cfg(&root);
 
-        let r = first.add("github.com/a/a".to_string(), false);
+        let r = first.add("github.com/a/a".to_string(), false, &[]);
 
         assert_eq!(r.err().is_none(), true);
         assert_eq!(first.repos().len(), 1);
@@ -243,7 +1180,7 @@ mod tests {
         let root = setup();
         let mut got = create_test_cfg(&root);
 
-        let r = got.add("github.com/a/a".to_string(), false);
+        let r = got.add("github.com/a/a".to_string(), false, &[]);
         assert_eq!(r.err().is_none(), true);
         assert_eq!(got.repos().len(), 1);
         assert_eq!(
@@ -262,7 +1199,7 @@ mod tests {
         );
 
         // Try adding duplicate
-        let r = got.add("github.com/a/a".to_string(), false);
+        let r = got.add("github.com/a/a".to_string(), false, &[]);
         assert_eq!(r.err().is_none(), true);
         assert_eq!(
             got.repos().to_owned(),
@@ -279,7 +1216,7 @@ mod tests {
             )])
         );
 
-        let r = got.add("github.com/b/b".to_string(), false);
+        let r = got.add("github.com/b/b".to_string(), false, &[]);
         assert_eq!(r.err().is_none(), true);
         assert_eq!(
             got.repos().to_owned(),
@@ -312,12 +1249,678 @@ mod tests {
         cleanup(root);
     }
 
+    #[test]
+    fn test_read_rejects_invalid_names() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        std::fs::write(
+            &p,
+            format!(
+                "metadata:\n  version: {}\n  root: {:?}\nrepos:\n  github.com/a/a:\n    name: ../../etc\n    url: git@github.com:a/a.git\n    pin: false\n    sha: ''\n",
+                CONFIG_VERSION,
+                root.path()
+            ),
+        )
+        .expect("failed to write config");
+
+        let cfg = Config::new(root.path().to_path_buf(), p.clone()).expect("new failed");
+        assert!(cfg.read(p).is_err());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_sanity_check() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        std::fs::write(
+            &p,
+            format!(
+                "metadata:\n  version: {}\n  root: {:?}\nrepos: {{}}\nrepo:\n  github.com/a/a: {{}}\n",
+                CONFIG_VERSION,
+                root.path()
+            ),
+        )
+        .expect("failed to write config");
+
+        let cfg = Config::new(root.path().to_path_buf(), p.clone()).expect("new failed");
+        let got = cfg.read(p).expect("read failed");
+        assert!(got.sanity_check().is_err());
+
+        let got = create_test_cfg(&root);
+        assert!(got.sanity_check().is_ok());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_write_backups() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+
+        // Creating the config doesn't back up the empty file.
+        assert!(!got.backup_path(1).exists());
+
+        for n in ["github.com/a/a", "github.com/b/b", "github.com/c/c"] {
+            got.add(n.to_string(), false, &[]).expect("add failed");
+        }
+        assert!(got.backup_path(3).exists());
+        assert!(!got.backup_path(4).exists());
+
+        // A change that does nothing doesn't rotate the backups.
+        got.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+        got.remove("github.com/x/x".to_string())
+            .expect("remove failed");
+        assert!(!got.backup_path(4).exists());
+
+        // The most recent backup has everything but the last add.
+        let b = got.read(got.backup_path(1)).expect("read failed");
+        assert_eq!(b.repos().len(), 2);
+
+        for n in 0..BACKUPS + 2 {
+            got.add(format!("github.com/x/{}", n), false, &[])
+                .expect("add failed");
+        }
+        assert!(got.backup_path(BACKUPS).exists());
+        assert!(!got.backup_path(BACKUPS + 1).exists());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_write_truncates() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+
+        got.add(
+            "github.com/a/aaaaaaaaaaaaaaaaaaaaaaaa".to_string(),
+            false,
+            &[],
+        )
+        .expect("add failed");
+        got.remove("github.com/a/aaaaaaaaaaaaaaaaaaaaaaaa".to_string())
+            .expect("remove failed");
+
+        let read = got.read(got.path()).expect("read failed");
+        assert_eq!(read.repos().len(), 0);
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_restore() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+
+        got.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+        got.remove("github.com/a/a".to_string())
+            .expect("remove failed");
+
+        let restored = got.restore(1).expect("restore failed");
+        assert_eq!(restored.repos, 1);
+        assert_eq!(got.read(got.path()).expect("read failed").repos().len(), 1);
+
+        // The restore itself can be undone.
+        let undone = got.restore(1).expect("restore failed");
+        assert_eq!(undone.repos, 0);
+
+        assert!(got.restore(BACKUPS + 1).is_err());
+
+        // A config that doesn't parse, the main reason to restore, can be
+        // restored over without being read.
+        stdfs::write(got.path(), "metadata: [\n").expect("failed to write");
+        assert!(got.read(got.path()).is_err());
+        let unread = Config::new(got.root(), got.path()).expect("new failed");
+        let restored = unread.restore(1).expect("restore failed");
+        assert_eq!(restored.repos, 1);
+        assert_eq!(got.read(got.path()).expect("read failed").repos().len(), 1);
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_tag() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+        let name = "github.com/a/a".to_string();
+        let tags = |c: &Config| c.repos()[&name].get_tags().to_vec();
+
+        got.add(name.clone(), false, &["oncall".to_string()])
+            .expect("add failed");
+        // Adding again keeps the tags the repo already has.
+        got.add(name.clone(), false, &["payments".to_string()])
+            .expect("add failed");
+        assert_eq!(tags(&got), vec!["oncall", "payments"]);
+
+        let change = got
+            .untag(name.clone(), &["oncall".to_string()])
+            .expect("untag failed");
+        assert!(change.changed);
+        assert_eq!(tags(&got), vec!["payments"]);
+
+        let change = got
+            .tag(name.clone(), &["payments".to_string()])
+            .expect("tag failed");
+        assert!(!change.changed);
+
+        assert!(got.tag(name.clone(), &["a b".to_string()]).is_err());
+        assert!(got
+            .tag("github.com/x/x".to_string(), &["a".to_string()])
+            .is_err());
+
+        let read = got.read(got.path()).expect("read failed");
+        assert_eq!(tags(&read), vec!["payments"]);
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_add_all() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+        let names = |v: &[&str]| v.iter().map(|n| n.to_string()).collect::<Vec<String>>();
+
+        // One invalid name leaves the config untouched.
+        let err = got
+            .add_all(&names(&["github.com/a/a", "github.com/b"]), false, &[])
+            .expect_err("add should fail");
+        assert!(err.to_string().contains("github.com/b:"));
+        assert!(got.read(got.path()).unwrap().repos().is_empty());
+
+        let changes = got
+            .add_all(
+                &names(&["github.com/a/a", "github.com/b/b", "github.com/a/a"]),
+                false,
+                &[],
+            )
+            .expect("add failed");
+        assert_eq!(changes.changes.len(), 2);
+        assert_eq!(got.read(got.path()).unwrap().repos().len(), 2);
+
+        let changes = got
+            .remove_all(&names(&["github.com/a/a", "github.com/x/x"]))
+            .expect("remove failed");
+        assert!(changes.changes[0].changed);
+        assert!(!changes.changes[1].changed);
+        let read = got.read(got.path()).unwrap();
+        assert_eq!(
+            read.repos().keys().collect::<Vec<_>>(),
+            vec!["github.com/b/b"]
+        );
+
+        // Re-adding a repo keeps what it already had.
+        let mut b = got.repos()["github.com/b/b"].clone();
+        b.pin(true)
+            .sha("abc1234".to_string())
+            .branch("dev".to_string())
+            .tags(vec!["web".to_string()])
+            .unwrap();
+        got.declare("github.com/b/b", &b).expect("declare failed");
+        let changes = got
+            .add_all(&names(&["github.com/b/b"]), false, &[])
+            .expect("add failed");
+        assert!(!changes.changes[0].changed);
+        let changes = got
+            .add_all(&names(&["github.com/b/b"]), false, &["oncall".to_string()])
+            .expect("add failed");
+        assert!(changes.changes[0].changed);
+        let read = got.read(got.path()).unwrap().repos()["github.com/b/b"].clone();
+        assert!(read.get_pin());
+        assert_eq!(read.get_sha(), "abc1234");
+        assert_eq!(read.get_branch(), "dev");
+        assert_eq!(read.get_tags(), ["oncall", "web"]);
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_rename() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+        got.add("github.com/a/a".to_string(), true, &["keep".to_string()])
+            .expect("add failed");
+        got.add("github.com/b/b".to_string(), false, &[])
+            .expect("add failed");
+
+        assert!(got.rename("github.com/a/a", "github.com/b/b").is_err());
+        assert!(got.rename("github.com/x/x", "github.com/y/y").is_err());
+
+        let r = got
+            .rename("github.com/a/a", "github.com/c/a")
+            .expect("rename failed");
+        assert_eq!(r.get_url(), "git@github.com:c/a.git");
+
+        let read = got.read(got.path()).expect("read failed");
+        assert!(!read.repos().contains_key("github.com/a/a"));
+        let moved = &read.repos()["github.com/c/a"];
+        assert!(moved.get_pin());
+        assert_eq!(moved.get_tags(), vec!["keep"]);
+
+        // A url in another form passes validation and keeps its form.
+        let p = got.path();
+        std::fs::write(
+            &p,
+            format!(
+                "metadata:\n  version: {}\n  root: {}\nrepos:\n  github.com/old/a:\n    url: https://github.com/old/a\n",
+                CONFIG_VERSION,
+                root.path().display()
+            ),
+        )
+        .expect("failed to write config");
+        let valid = |p: &Path| {
+            crate::validate::validate(p, root.path())
+                .expect("validate failed")
+                .is_valid()
+        };
+        assert!(valid(&p));
+        let mut got = got.read(p.clone()).expect("read failed");
+        let r = got
+            .rename("github.com/old/a", "github.com/new/b")
+            .expect("rename failed");
+        assert_eq!(r.get_url(), "https://github.com/new/b");
+        assert!(valid(&p));
+        assert_eq!(
+            got.read(p.clone()).expect("read failed").repos()["github.com/new/b"].get_url(),
+            "https://github.com/new/b"
+        );
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_import() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+        got.add("github.com/a/a".to_string(), false, &["keep".to_string()])
+            .expect("add failed");
+
+        let repos: Vec<Repo> = ["github.com/a/a", "github.com/b/b", "github.com/b/b"]
+            .iter()
+            .map(|n| {
+                Repo::new()
+                    .name(n.to_string())
+                    .expect("name failed")
+                    .branch("main".to_string())
+                    .to_owned()
+            })
+            .collect();
+
+        let added = got.import(&repos).expect("import failed");
+        assert_eq!(added, vec!["github.com/b/b"]);
+
+        let read = got.read(got.path()).expect("read failed");
+        assert_eq!(read.repos().len(), 2);
+        assert_eq!(read.repos()["github.com/b/b"].get_branch(), "main");
+        // Repos the config already had are left as they were.
+        assert_eq!(read.repos()["github.com/a/a"].get_tags(), ["keep"]);
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_history() {
+        let root = setup();
+        let mut got = create_test_cfg(&root);
+        assert!(got.history(None).is_err());
+
+        let p = got.path();
+        let text = stdfs::read_to_string(&p).expect("read failed");
+        stdfs::write(
+            &p,
+            text.replace("metadata:\n", "metadata:\n  history: true\n"),
+        )
+        .expect("write failed");
+        got = got.read(p.clone()).expect("read failed");
+
+        got.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+        got.add("github.com/b/b".to_string(), false, &[])
+            .expect("add failed");
+
+        let h = got.history(None).expect("history failed").to_string();
+        let messages: Vec<&str> = h
+            .lines()
+            .filter(|l| !l.starts_with(' '))
+            .map(|l| l.split_once(' ').unwrap().1.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(
+            messages,
+            vec!["add github.com/b/b", "add github.com/a/a", history::OUTSIDE]
+        );
+        assert!(h.contains("\n  + github.com/b/b\n"));
+
+        let undo = got.undo().expect("undo failed");
+        assert!(undo.to_string().contains("add github.com/b/b"));
+        assert_eq!(got.read(p.clone()).expect("read failed").repos().len(), 1);
+
+        // Undoing the undo redoes the change.
+        got.undo().expect("undo failed");
+        assert_eq!(got.read(p.clone()).expect("read failed").repos().len(), 2);
+
+        // Edits made outside of gitrs are recorded before they're undone.
+        let text = stdfs::read_to_string(&p).expect("read failed");
+        stdfs::write(&p, text.replace("github.com/a/a", "github.com/c/c")).expect("write failed");
+        got.undo().expect("undo failed");
+        assert!(stdfs::read_to_string(&p)
+            .expect("read failed")
+            .contains("github.com/a/a"));
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_write_preserves_comments() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        let text = format!(
+            "# Team repos.\nmetadata:\n  version: {}\n  root: {}\nrepos:\n  # Keep me.\n  github.com/b/b:\n    name: github.com/b/b\n    url: git@github.com:b/b.git\n    pin: false # inline\n    sha: ''\n",
+            CONFIG_VERSION,
+            root.path().display()
+        );
+        std::fs::write(&p, &text).expect("failed to write config");
+
+        let mut got = Config::new(root.path().to_path_buf(), p.clone())
+            .expect("new failed")
+            .read(p.clone())
+            .expect("read failed");
+
+        got.add("github.com/c/c".to_string(), false, &[])
+            .expect("add failed");
+        got.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+
+        let written = std::fs::read_to_string(&p).expect("read failed");
+        assert!(written.starts_with("# Team repos.\n"));
+        assert!(written.contains("  # Keep me.\n  github.com/b/b:\n"));
+        assert!(written.contains("pin: false # inline\n"));
+
+        let a = written.find("github.com/a/a:").unwrap();
+        let b = written.find("github.com/b/b:").unwrap();
+        let c = written.find("github.com/c/c:").unwrap();
+        assert!(a < b && b < c);
+
+        got.remove("github.com/a/a".to_string())
+            .expect("remove failed");
+        got.remove("github.com/c/c".to_string())
+            .expect("remove failed");
+        assert_eq!(std::fs::read_to_string(&p).expect("read failed"), text);
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_read_list() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        let text = format!(
+            "metadata:\n  version: {}\n  root: {}\n  last_sync: 2023-06-01T00:00:00Z\nrepos:\n- name: github.com/b/b\n  pin: true\n  sha: abc\n",
+            CONFIG_VERSION,
+            root.path().display()
+        );
+        std::fs::write(&p, &text).expect("failed to write config");
+
+        let mut got = Config::new(root.path().to_path_buf(), p.clone())
+            .expect("new failed")
+            .read(p.clone())
+            .expect("read failed");
+
+        let r = &got.repos()["github.com/b/b"];
+        assert_eq!(r.get_url(), "git@github.com:b/b.git");
+        assert!(r.get_pin());
+        assert_eq!(r.get_sha(), "abc");
+        assert_eq!(
+            got.metadata.last_sync,
+            Some("2023-06-01T00:00:00Z".to_string())
+        );
+
+        // Writes keep the list shape and leave other entries alone.
+        got.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+        let written = std::fs::read_to_string(&p).expect("read failed");
+        assert!(written.contains("repos:\n- name: github.com/a/a\n"));
+        assert!(written.contains("- name: github.com/b/b\n  pin: true\n  sha: abc\n"));
+        assert!(written.contains("last_sync: 2023-06-01T00:00:00Z\n"));
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_read_list_duplicate() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        let text = format!(
+            "metadata:\n  version: {}\n  root: {}\nrepos:\n- name: github.com/b/b\n- name: github.com/b/b\n  pin: true\n",
+            CONFIG_VERSION,
+            root.path().display()
+        );
+        std::fs::write(&p, &text).expect("failed to write config");
+
+        let got = Config::new(root.path().to_path_buf(), p.clone())
+            .expect("new failed")
+            .read(p.clone());
+        assert!(format!("{:#}", got.unwrap_err()).contains("duplicate repo"));
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_repos_format() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        let text = format!(
+            "metadata:\n  version: {}\n  root: {}\n  repos_format: map\nrepos:\n- name: github.com/b/b\n",
+            CONFIG_VERSION,
+            root.path().display()
+        );
+        std::fs::write(&p, &text).expect("failed to write config");
+
+        let mut got = Config::new(root.path().to_path_buf(), p.clone())
+            .expect("new failed")
+            .read(p.clone())
+            .expect("read failed");
+        got.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+
+        let written = std::fs::read_to_string(&p).expect("read failed");
+        assert!(written.contains("repos:\n  github.com/a/a:\n"));
+        assert!(written.contains("  github.com/b/b:\n    name: github.com/b/b\n"));
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_locate() {
+        let root = setup();
+        let xdg = root.path().join("xdg");
+        let vars = |config: Option<&str>| {
+            let xdg = xdg.display().to_string();
+            let config = config.map(|c| c.to_string());
+            move |k: &str| match k {
+                "XDG_CONFIG_HOME" => Some(xdg.clone()),
+                "GITRS_CONFIG" => config.clone(),
+                "WORK" => Some("/work".to_string()),
+                _ => None,
+            }
+        };
+
+        let got = locate_with(None, root.path(), vars(None)).expect("locate failed");
+        assert_eq!(got.path, root.path().join(CONFIG_FILE));
+        assert_eq!(got.source, Source::Root);
+        assert!(!got.exists);
+
+        create_dir_all(xdg.join("gitrs")).expect("failed to create dir");
+        stdfs::write(xdg.join("gitrs/config.yaml"), "").expect("failed to write");
+        let got = locate_with(None, root.path(), vars(None)).expect("locate failed");
+        assert_eq!(got.path, xdg.join("gitrs/config.yaml"));
+        assert_eq!(got.source, Source::Xdg);
+        assert!(got.exists);
+
+        let got =
+            locate_with(None, root.path(), vars(Some("$WORK/gitrs.yaml"))).expect("locate failed");
+        assert_eq!(got.path, PathBuf::from("/work/gitrs.yaml"));
+        assert_eq!(got.source, Source::Env);
+
+        let got = locate_with(
+            Some(PathBuf::from("/flag.yaml")),
+            root.path(),
+            vars(Some("/env.yaml")),
+        )
+        .expect("locate failed");
+        assert_eq!(got.path, PathBuf::from("/flag.yaml"));
+        assert_eq!(got.source, Source::Flag);
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_read_expands_root() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        let var = |k: &str| (k == "GITRS_TEST_ROOT").then(|| root.path().display().to_string());
+        let text = format!(
+            "metadata:\n  version: {}\n  root: ${{GITRS_TEST_ROOT}}\nrepos: {{}}\n",
+            CONFIG_VERSION
+        );
+        std::fs::write(&p, &text).expect("failed to write config");
+
+        let mut got = Config::new(root.path().to_path_buf(), p.clone())
+            .expect("new failed")
+            .read_with(p.clone(), var)
+            .expect("read failed");
+        assert_eq!(got.root(), root.path().to_path_buf());
+        assert_eq!(
+            root_of_with(&p, var).expect("root_of failed"),
+            root.path().to_path_buf()
+        );
+
+        // Writes keep the unexpanded root.
+        got.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+        let written = std::fs::read_to_string(&p).expect("read failed");
+        assert!(written.contains("root: ${GITRS_TEST_ROOT}\n"));
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_include() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        std::fs::write(
+            root.path().join("team.yaml"),
+            "repos:\n- name: github.com/team/a\n- name: github.com/team/b\n- name: github.com/team/c\n",
+        )
+        .expect("failed to write manifest");
+        let text = format!(
+            "metadata:\n  version: {}\n  root: {}\ninclude:\n- team.yaml\nexclude:\n- github.com/team/c\n# Pinned for the release.\nrepos:\n  github.com/team/b:\n    pin: true\n    sha: abc1234\n",
+            CONFIG_VERSION,
+            root.path().display()
+        );
+        std::fs::write(&p, &text).expect("failed to write config");
+
+        let mut got = Config::new(root.path().to_path_buf(), p.clone())
+            .expect("new failed")
+            .read(p.clone())
+            .expect("read failed");
+
+        let mut names: Vec<&String> = got.repos().keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["github.com/team/a", "github.com/team/b"]);
+
+        let b = &got.repos()["github.com/team/b"];
+        assert!(b.get_pin());
+        assert_eq!(b.get_sha(), "abc1234");
+        assert_eq!(b.get_url(), "git@github.com:team/b.git");
+        assert_eq!(
+            got.sources()["github.com/team/a"],
+            root.path().join("team.yaml").display().to_string()
+        );
+
+        // Removing an included repo excludes it rather than touching the manifest.
+        let change = got
+            .remove("github.com/team/a".to_string())
+            .expect("remove failed");
+        assert!(change.changed);
+        assert!(!got.repos().contains_key("github.com/team/a"));
+        assert_eq!(
+            std::fs::read_to_string(&p).expect("read failed"),
+            text.replace(
+                "- github.com/team/c\n",
+                "- github.com/team/c\n- github.com/team/a\n"
+            )
+        );
+
+        got.add("github.com/me/me".to_string(), false, &[])
+            .expect("add failed");
+        assert_eq!(got.sources()["github.com/me/me"], p.display().to_string());
+
+        let read = got.read(p.clone()).expect("read failed");
+        let mut names: Vec<&String> = read.repos().keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["github.com/me/me", "github.com/team/b"]);
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("team.yaml")).expect("read failed"),
+            "repos:\n- name: github.com/team/a\n- name: github.com/team/b\n- name: github.com/team/c\n"
+        );
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(CONFIG_VERSION, migrate::current());
+    }
+
+    #[test]
+    fn test_read_newer_version() {
+        let root = setup();
+        let p = root.path().join("test.yaml");
+
+        std::fs::write(
+            &p,
+            format!(
+                "metadata:\n  version: v99\n  root: {:?}\nrepos: {{}}\n",
+                root.path()
+            ),
+        )
+        .expect("failed to write config");
+
+        let cfg = Config::new(root.path().to_path_buf(), p.clone()).expect("new failed");
+        let got = cfg.read(p.clone());
+        assert!(got.unwrap_err().to_string().contains("newer gitrs"));
+        assert!(migration(root.path().to_path_buf(), p, true).is_err());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_migration_current() {
+        let root = setup();
+        let cfg = create_test_cfg(&root);
+
+        let got = migration(root.path().to_path_buf(), cfg.path(), true).expect("check failed");
+        assert!(!got.needed());
+        assert!(!got.migrated);
+
+        cleanup(root);
+    }
+
     #[test]
     fn test_remove() {
         let root = setup();
         let mut got = create_test_cfg(&root);
 
-        let r = got.add("github.com/a/a".to_string(), false);
+        let r = got.add("github.com/a/a".to_string(), false, &[]);
         assert_eq!(r.err().is_none(), true);
         assert_eq!(got.repos().len(), 1);
         assert_eq!(
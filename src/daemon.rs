@@ -0,0 +1,404 @@
+use crate::config::Config;
+use crate::fs;
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, NaiveTime};
+use log::{error, info};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// STATUS_FILE is written to the root after every run so that prompt tools can
+/// cheaply check on the daemon without running gitrs.
+pub const STATUS_FILE: &str = ".gitrs.status.json";
+
+/// STATUS_VERSION is the version of the status file's schema. Like the JSON
+/// output's, it's bumped any time a field is removed or changes meaning.
+pub const STATUS_VERSION: &str = "v1";
+
+// How often the daemon wakes up to check whether it has been asked to shut down.
+const TICK: Duration = Duration::from_secs(1);
+
+/// QuietHours is a daily, local time window e.g., "22:00-07:00", in which the
+/// daemon doesn't sync. Windows may wrap midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        match self.start <= self.end {
+            true => self.start <= t && t < self.end,
+            false => t >= self.start || t < self.end,
+        }
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("quiet hours should be of the format HH:MM-HH:MM"))?;
+
+        Ok(QuietHours {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M")
+                .with_context(|| format!("invalid start time: {}", start))?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M")
+                .with_context(|| format!("invalid end time: {}", end))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    interval: Duration,
+    jitter: Duration,
+    quiet_hours: Option<QuietHours>,
+    skip_on_battery: bool,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Options {
+            interval: Duration::from_secs(60 * 60),
+            jitter: Duration::from_secs(0),
+            quiet_hours: None,
+            skip_on_battery: true,
+        }
+    }
+
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+
+    /// jitter is the maximum random delay added to each interval so that many
+    /// machines started at once don't all fetch at once.
+    pub fn jitter(&mut self, jitter: Duration) -> &mut Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn quiet_hours(&mut self, quiet_hours: Option<QuietHours>) -> &mut Self {
+        self.quiet_hours = quiet_hours;
+        self
+    }
+
+    pub fn skip_on_battery(&mut self, skip: bool) -> &mut Self {
+        self.skip_on_battery = skip;
+        self
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// DaemonStatus is the content of the status file. Times are unix seconds.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct DaemonStatus {
+    schema_version: String,
+    pid: u32,
+    state: State,
+    last_run: Option<u64>,
+    last_success: Option<u64>,
+    /// skipped is the reason the last run was skipped, if it was.
+    skipped: Option<String>,
+    /// failed is the number of repos the last sync failed to sync.
+    failed: usize,
+    /// last_error is why the last sync failed outright e.g., an unreadable
+    /// config. None once a sync completes.
+    last_error: Option<String>,
+    next_run: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    #[default]
+    Idle,
+    Syncing,
+    Stopped,
+}
+
+impl DaemonStatus {
+    // record records the result of the run's sync: the number of repos it
+    // failed to sync, or None when another sync held the root.
+    fn record(&mut self, res: Result<Option<usize>>) {
+        match res {
+            Ok(Some(failed)) => {
+                self.failed = failed;
+                self.last_error = None;
+                if failed == 0 {
+                    self.last_success = self.last_run;
+                }
+            }
+            Ok(None) => self.skipped = Some("lock held".to_owned()),
+            Err(e) => {
+                error!("Failed to sync: {:#}", e);
+                self.failed = 0;
+                self.last_error = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    fn write(&self, root: &Path) -> Result<()> {
+        fs::write_atomic(
+            root.join(STATUS_FILE).as_path(),
+            serde_json::to_string(self)?.as_bytes(),
+        )
+    }
+}
+
+/// run syncs the root on an interval until SIGTERM or SIGINT is received.
+///
+/// The config is re-read before every sync so that changes are picked up.
+/// A signal aborts the in-flight clone, if any, and stops the sync before
+/// the next repo; partial clones are cleaned up.
+pub fn run(cfg: Config, opts: &Options) -> Result<()> {
+    for sig in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(sig, fs::cancel_flag())
+            .context("failed to register signal handler")?;
+    }
+
+    let root = cfg.root();
+    let mut status = DaemonStatus {
+        schema_version: STATUS_VERSION.to_owned(),
+        pid: std::process::id(),
+        ..Default::default()
+    };
+
+    info!("Starting daemon for root: {:?}", root);
+
+    while !fs::cancelled() {
+        status.last_run = Some(now());
+        status.skipped = skip_reason(&root, opts, power_supply().as_path());
+
+        if status.skipped.is_none() {
+            status.state = State::Syncing;
+            status.write(&root)?;
+
+            status.record(sync(&cfg));
+        }
+
+        if let Some(r) = &status.skipped {
+            info!("Skipped sync: {}", r);
+        }
+
+        let wait = next(opts);
+        status.state = State::Idle;
+        status.next_run = Some(now().saturating_add(wait.as_secs()));
+        status.write(&root)?;
+
+        sleep_until_cancelled(wait);
+    }
+
+    info!("Stopping daemon");
+    status.state = State::Stopped;
+    status.next_run = None;
+    status.write(&root)
+}
+
+// sync returns the number of repos that failed to sync, or None if another
+// sync holds the lock.
+fn sync(cfg: &Config) -> Result<Option<usize>> {
+    let root = cfg.root();
+    let _lock = match fs::lock(root.as_path())? {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+
+    cfg.fetch_manifests().context("failed to fetch manifests")?;
+    let cfg = cfg.read(cfg.path()).context("failed to read config")?;
+    let mut guard = fs::Guard::new();
+    guard.sanity(cfg.sanity_check().err().map(|e| e.to_string()));
+
+    let report = fs::sync(root, cfg.repos(), &false, &guard, &[])?;
+    Ok(Some(report.failed()))
+}
+
+fn skip_reason(root: &Path, opts: &Options, power_supply: &Path) -> Option<String> {
+    if let Some(q) = opts.quiet_hours {
+        if q.contains(Local::now().time()) {
+            return Some("quiet hours".to_owned());
+        }
+    }
+
+    if opts.skip_on_battery && on_battery(power_supply) {
+        return Some("on battery".to_owned());
+    }
+
+    // Check the lock up front too, so a held lock doesn't mark the root as
+    // syncing in the status file.
+    match fs::lock(root) {
+        Ok(Some(_)) => None,
+        Ok(None) => Some("lock held".to_owned()),
+        Err(e) => Some(format!("{:#}", e)),
+    }
+}
+
+fn next(opts: &Options) -> Duration {
+    let jitter = match opts.jitter.is_zero() {
+        true => Duration::ZERO,
+        false => rand::thread_rng().gen_range(Duration::ZERO..=opts.jitter),
+    };
+    opts.interval.saturating_add(jitter)
+}
+
+fn sleep_until_cancelled(d: Duration) {
+    let mut remaining = d;
+    while !remaining.is_zero() && !fs::cancelled() {
+        let t = remaining.min(TICK);
+        sleep(t);
+        remaining -= t;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn power_supply() -> PathBuf {
+    PathBuf::from("/sys/class/power_supply")
+}
+
+// on_battery reports whether a battery under the sysfs power_supply directory
+// is discharging. Machines without one, or without sysfs, are never on battery.
+fn on_battery(power_supply: &Path) -> bool {
+    let entries = match std::fs::read_dir(power_supply) {
+        Ok(e) => e,
+        Err(_) => return false,
+    };
+
+    entries.flatten().any(|e| {
+        let read = |f: &str| std::fs::read_to_string(e.path().join(f)).unwrap_or_default();
+        read("type").trim() == "Battery" && read("status").trim() == "Discharging"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::{tempdir, TempDir};
+
+    fn setup() -> TempDir {
+        tempdir().expect("Failed to create tempdir")
+    }
+
+    fn t(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").expect("invalid time")
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let q: QuietHours = "22:00-07:00".parse().expect("failed to parse");
+        assert!(q.contains(t("23:30")));
+        assert!(q.contains(t("03:00")));
+        assert!(!q.contains(t("07:00")));
+        assert!(!q.contains(t("12:00")));
+
+        let q: QuietHours = "12:00-13:00".parse().expect("failed to parse");
+        assert!(q.contains(t("12:30")));
+        assert!(!q.contains(t("13:30")));
+
+        assert!("12:00".parse::<QuietHours>().is_err());
+        assert!("25:00-01:00".parse::<QuietHours>().is_err());
+    }
+
+    #[test]
+    fn test_next_saturates() {
+        let mut opts = Options::new();
+        opts.interval(Duration::MAX).jitter(Duration::from_secs(60));
+        assert_eq!(next(&opts), Duration::MAX);
+    }
+
+    #[test]
+    fn test_on_battery() {
+        let root = setup();
+        let p = root.path();
+
+        assert!(!on_battery(p.join("doesnt-exist").as_path()));
+
+        create_dir_all(p.join("AC")).unwrap();
+        write(p.join("AC/type"), "Mains\n").unwrap();
+        create_dir_all(p.join("BAT0")).unwrap();
+        write(p.join("BAT0/type"), "Battery\n").unwrap();
+        write(p.join("BAT0/status"), "Charging\n").unwrap();
+        assert!(!on_battery(p));
+
+        write(p.join("BAT0/status"), "Discharging\n").unwrap();
+        assert!(on_battery(p));
+    }
+
+    #[test]
+    fn test_skip_reason_lock_held() {
+        let root = setup();
+        let p = root.path();
+        let opts = Options::new();
+
+        assert_eq!(skip_reason(p, &opts, p.join("none").as_path()), None);
+
+        let _lock = fs::lock(p).expect("lock failed").expect("lock held");
+        assert_eq!(
+            skip_reason(p, &opts, p.join("none").as_path()),
+            Some("lock held".to_string())
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let mut s = DaemonStatus {
+            last_run: Some(1),
+            ..Default::default()
+        };
+        s.record(Ok(Some(2)));
+        assert_eq!((s.failed, s.last_success), (2, None));
+
+        // A sync that fails outright doesn't look like the last one.
+        s.record(Err(anyhow::anyhow!("failed to read config")));
+        assert_eq!(s.failed, 0);
+        assert_eq!(s.last_error.as_deref(), Some("failed to read config"));
+
+        s.last_run = Some(3);
+        s.record(Ok(Some(0)));
+        assert_eq!(s.last_error, None);
+        assert_eq!(s.last_success, Some(3));
+
+        s.record(Ok(None));
+        assert_eq!(s.skipped.as_deref(), Some("lock held"));
+    }
+
+    #[test]
+    fn test_status_file() {
+        let root = setup();
+        let want = DaemonStatus {
+            schema_version: STATUS_VERSION.to_owned(),
+            pid: 1,
+            state: State::Idle,
+            last_run: Some(2),
+            next_run: Some(3),
+            ..Default::default()
+        };
+
+        want.write(root.path()).expect("write failed");
+
+        let got: DaemonStatus = serde_json::from_str(
+            &std::fs::read_to_string(root.path().join(STATUS_FILE)).expect("read failed"),
+        )
+        .expect("failed to parse");
+        assert_eq!(got, want);
+    }
+}
@@ -0,0 +1,549 @@
+use anyhow::{anyhow, Result};
+use serde_yaml::{Mapping, Value};
+
+// The indentation used for repo entries when the config doesn't have any yet.
+const DEFAULT_INDENT: usize = 2;
+
+/// Document is the config file's text, edited line by line so that comments,
+/// blank lines and the order of entries in `repos` are preserved.
+///
+/// Only a block-style `repos` mapping or list is supported. Anything else
+/// returns an error, so callers can fall back to rewriting the whole file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    lines: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Section {
+    // header is the index of the `repos:` line.
+    header: usize,
+    // end is the index of the first line after the section.
+    end: usize,
+    // list is true when the entries are list items rather than mapping entries.
+    list: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    // key is the mapping key or, for list items, the item's name.
+    key: String,
+    // start is the index of the first comment directly above the key, or of
+    // the key itself.
+    start: usize,
+    // end is the index of the first line after the entry's value.
+    end: usize,
+}
+
+impl Document {
+    pub fn parse(s: &str) -> Self {
+        Document {
+            lines: s.lines().map(|l| l.to_owned()).collect(),
+        }
+    }
+
+    /// remove removes the entry for key, along with the comments directly
+    /// above it. Returns false if there was no entry for key.
+    pub fn remove(&mut self, key: &str) -> Result<bool> {
+        let (_, entries, _) = self.entries()?;
+
+        match entries.into_iter().find(|e| e.key == key) {
+            Some(e) => {
+                self.lines.drain(e.start..e.end);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// upsert replaces the value of the entry for key, keeping the comments
+    /// above it, or inserts a new entry before the first entry that sorts
+    /// after it. value is rendered as a block-style YAML mapping entry or,
+    /// when `repos` is a list, as a list item.
+    pub fn upsert(&mut self, key: &str, value: &Value) -> Result<()> {
+        let (section, entries, indent) = self.entries()?;
+        let block = match section.list {
+            true => render_item(value, indent)?,
+            false => render(key, value, indent)?,
+        };
+
+        if let Some(e) = entries.iter().find(|e| e.key == key) {
+            let key_line = (e.start..e.end)
+                .find(|i| !is_comment(&self.lines[*i]))
+                .unwrap_or(e.start);
+            self.lines.splice(key_line..e.end, block);
+            return Ok(());
+        }
+
+        let at = match entries.iter().find(|e| e.key.as_str() > key) {
+            Some(e) => e.start,
+            None => entries.last().map(|e| e.end).unwrap_or(section.header + 1),
+        };
+        self.lines.splice(at..at, block);
+        Ok(())
+    }
+
+    // entries returns the `repos` section, its entries in file order and the
+    // indentation of the entries.
+    fn entries(&mut self) -> Result<(Section, Vec<Entry>, usize)> {
+        let section = self.section()?;
+
+        let first = (section.header + 1..section.end)
+            .map(|i| &self.lines[i])
+            .find(|l| !is_blank(l) && !is_comment(l));
+        let indent = match (first, section.list) {
+            (Some(l), _) => indentation(l),
+            (None, true) => 0,
+            (None, false) => DEFAULT_INDENT,
+        };
+        let list = first.map_or(section.list, |l| l.trim_start().starts_with('-'));
+        let section = Section { list, ..section };
+
+        let mut entries = Vec::new();
+        let mut i = section.header + 1;
+        while i < section.end {
+            let l = &self.lines[i];
+            if is_blank(l) || is_comment(l) || indentation(l) != indent {
+                i += 1;
+                continue;
+            }
+
+            if l.trim_start().starts_with('-') != list {
+                return Err(anyhow!("repos mixes list items and mapping entries"));
+            }
+
+            let mut start = i;
+            while start > section.header + 1
+                && is_comment(&self.lines[start - 1])
+                && indentation(&self.lines[start - 1]) == indent
+            {
+                start -= 1;
+            }
+
+            let mut end = i + 1;
+            while end < section.end {
+                let l = &self.lines[end];
+                if !is_blank(l) && !is_comment(l) && indentation(l) <= indent {
+                    break;
+                }
+                end += 1;
+            }
+            // Trailing blank lines and comments belong to whatever comes next.
+            while end > i + 1
+                && (is_blank(&self.lines[end - 1])
+                    || (is_comment(&self.lines[end - 1])
+                        && indentation(&self.lines[end - 1]) <= indent))
+            {
+                end -= 1;
+            }
+
+            let key = match list {
+                true => item_key(&self.lines[i..end], indent)?,
+                false => key(&self.lines[i])?,
+            };
+
+            entries.push(Entry { key, start, end });
+            i = end;
+        }
+
+        Ok((section, entries, indent))
+    }
+
+    // section finds the top-level `repos:` line and the lines that belong to it.
+    // An empty inline mapping i.e., `repos: {}`, is expanded to a block.
+    fn section(&mut self) -> Result<Section> {
+        let header = self
+            .lines
+            .iter()
+            .position(|l| l.starts_with("repos:"))
+            .ok_or_else(|| anyhow!("no top-level repos key"))?;
+
+        let value = strip_comment(&self.lines[header]["repos:".len()..]);
+        let list = match value.trim() {
+            "" => false,
+            "{}" => {
+                self.lines[header] = "repos:".to_owned();
+                false
+            }
+            "[]" => {
+                self.lines[header] = "repos:".to_owned();
+                true
+            }
+            v => return Err(anyhow!("repos isn't a block mapping or list: {}", v)),
+        };
+
+        let end = self.end_of(header);
+        Ok(Section { header, end, list })
+    }
+
+    // end_of returns the index of the first line after the top-level key on
+    // line header and its block-style value.
+    fn end_of(&self, header: usize) -> usize {
+        (header + 1..self.lines.len())
+            .find(|i| {
+                let l = &self.lines[*i];
+                !is_blank(l) && !is_comment(l) && indentation(l) == 0 && !l.starts_with('-')
+            })
+            .unwrap_or(self.lines.len())
+    }
+
+    /// exclude appends pattern to the top-level `exclude` list, adding the
+    /// list above `repos` when there isn't one.
+    pub fn exclude(&mut self, pattern: &str) -> Result<()> {
+        let item = |indent: usize| -> Result<String> {
+            let v = serde_yaml::to_string(&Value::String(pattern.to_owned()))?;
+            Ok(format!("{}- {}", " ".repeat(indent), v.trim_end()))
+        };
+
+        let header = match self.lines.iter().position(|l| l.starts_with("exclude:")) {
+            Some(h) => h,
+            None => {
+                // Above `repos` and the comments directly above it.
+                let mut at = self
+                    .lines
+                    .iter()
+                    .position(|l| l.starts_with("repos:"))
+                    .unwrap_or(self.lines.len());
+                while at > 0 && is_comment(&self.lines[at - 1]) {
+                    at -= 1;
+                }
+                self.lines.splice(at..at, ["exclude:".to_owned(), item(0)?]);
+                return Ok(());
+            }
+        };
+
+        match strip_comment(&self.lines[header]["exclude:".len()..]).trim() {
+            "" => {}
+            "[]" => self.lines[header] = "exclude:".to_owned(),
+            v => return Err(anyhow!("exclude isn't a block list: {}", v)),
+        }
+
+        let items: Vec<usize> = (header + 1..self.end_of(header))
+            .filter(|i| !is_blank(&self.lines[*i]) && !is_comment(&self.lines[*i]))
+            .collect();
+        let (at, indent) = match (items.first(), items.last()) {
+            (Some(first), Some(last)) => (*last + 1, indentation(&self.lines[*first])),
+            _ => (header + 1, 0),
+        };
+        if items
+            .iter()
+            .any(|i| !self.lines[*i].trim_start().starts_with('-'))
+        {
+            return Err(anyhow!("exclude has entries that aren't plain list items"));
+        }
+        self.lines.insert(at, item(indent)?);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Document {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for l in &self.lines {
+            writeln!(f, "{}", l)?;
+        }
+        Ok(())
+    }
+}
+
+// render renders key and value as a block-style mapping entry, indented.
+fn render(key: &str, value: &Value, indent: usize) -> Result<Vec<String>> {
+    let mut m = Mapping::new();
+    m.insert(Value::String(key.to_owned()), value.clone());
+
+    let pad = " ".repeat(indent);
+    Ok(serde_yaml::to_string(&m)?
+        .lines()
+        .map(|l| format!("{}{}", pad, l))
+        .collect())
+}
+
+// render_item renders value as a block-style list item, indented.
+fn render_item(value: &Value, indent: usize) -> Result<Vec<String>> {
+    let pad = " ".repeat(indent);
+    Ok(serde_yaml::to_string(&[value])?
+        .lines()
+        .map(|l| format!("{}{}", pad, l))
+        .collect())
+}
+
+// item_key parses the name of a list item from its lines.
+fn item_key(lines: &[String], indent: usize) -> Result<String> {
+    let text: Vec<&str> = lines
+        .iter()
+        .map(|l| l.get(indent..).unwrap_or_default())
+        .collect();
+    let items: Vec<Mapping> = serde_yaml::from_str(&text.join("\n"))
+        .map_err(|e| anyhow!("failed to parse item {:?}: {}", lines[0], e))?;
+
+    match items.first().and_then(|m| m.get("name")) {
+        Some(Value::String(k)) => Ok(k.to_owned()),
+        _ => Err(anyhow!("item {:?} doesn't have a string name", lines[0])),
+    }
+}
+
+// key parses the key of a mapping entry line, which may be quoted.
+fn key(l: &str) -> Result<String> {
+    let m: Mapping = serde_yaml::from_str(l.trim())
+        .map_err(|e| anyhow!("failed to parse entry {:?}: {}", l, e))?;
+
+    match m.keys().next() {
+        Some(Value::String(k)) => Ok(k.to_owned()),
+        _ => Err(anyhow!("entry {:?} doesn't have a string key", l)),
+    }
+}
+
+fn strip_comment(s: &str) -> &str {
+    match s.find(" #") {
+        Some(i) => &s[..i],
+        None if s.trim_start().starts_with('#') => "",
+        None => s,
+    }
+}
+
+fn indentation(l: &str) -> usize {
+    l.len() - l.trim_start().len()
+}
+
+fn is_blank(l: &str) -> bool {
+    l.trim().is_empty()
+}
+
+fn is_comment(l: &str) -> bool {
+    l.trim_start().starts_with('#')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# Managed by gitrs.
+metadata:
+  version: v1beta
+  root: /src
+
+repos:
+  # The tool itself.
+  github.com/mccurdyc/gitrs:
+    name: github.com/mccurdyc/gitrs
+    url: git@github.com:mccurdyc/gitrs.git
+    pin: false # not yet
+    sha: ''
+
+  # Team repos.
+  github.com/team/b:
+    name: github.com/team/b
+    url: git@github.com:team/b.git
+    pin: false
+    sha: ''
+# Trailing comment.
+";
+
+    fn value(name: &str, pin: bool) -> Value {
+        let (host, path) = name.split_once('/').unwrap();
+        serde_yaml::from_str(&format!(
+            "{{name: {}, url: 'git@{}:{}.git', pin: {}, sha: ''}}",
+            name, host, path, pin
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(Document::parse(CONFIG).to_string(), CONFIG);
+    }
+
+    #[test]
+    fn test_insert_sorted() {
+        let mut d = Document::parse(CONFIG);
+        d.upsert("github.com/team/a", &value("github.com/team/a", false))
+            .expect("upsert failed");
+
+        let got = d.to_string();
+        let want = CONFIG.replace(
+            "  # Team repos.\n",
+            "  github.com/team/a:
+    name: github.com/team/a
+    url: git@github.com:team/a.git
+    pin: false
+    sha: ''
+  # Team repos.\n",
+        );
+        assert_eq!(got, want);
+
+        let mut d = Document::parse(CONFIG);
+        d.upsert("github.com/z/z", &value("github.com/z/z", false))
+            .expect("upsert failed");
+        assert!(d.to_string().ends_with(
+            "    sha: ''
+  github.com/z/z:
+    name: github.com/z/z
+    url: git@github.com:z/z.git
+    pin: false
+    sha: ''
+# Trailing comment.\n"
+        ));
+    }
+
+    #[test]
+    fn test_update() {
+        let mut d = Document::parse(CONFIG);
+        d.upsert("github.com/team/b", &value("github.com/team/b", true))
+            .expect("upsert failed");
+
+        let got = d.to_string();
+        assert_eq!(
+            got,
+            CONFIG.replace(
+                "url: git@github.com:team/b.git\n    pin: false",
+                "url: git@github.com:team/b.git\n    pin: true"
+            )
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut d = Document::parse(CONFIG);
+        assert!(d
+            .remove("github.com/mccurdyc/gitrs")
+            .expect("remove failed"));
+        assert!(!d.remove("github.com/x/x").expect("remove failed"));
+
+        let got = d.to_string();
+        assert_eq!(
+            got,
+            "\
+# Managed by gitrs.
+metadata:
+  version: v1beta
+  root: /src
+
+repos:
+
+  # Team repos.
+  github.com/team/b:
+    name: github.com/team/b
+    url: git@github.com:team/b.git
+    pin: false
+    sha: ''
+# Trailing comment.
+"
+        );
+    }
+
+    #[test]
+    fn test_empty_repos() {
+        let mut d = Document::parse("metadata:\n  version: v1beta\nrepos: {}\n");
+        d.upsert("github.com/a/a", &value("github.com/a/a", false))
+            .expect("upsert failed");
+
+        assert_eq!(
+            d.to_string(),
+            "metadata:
+  version: v1beta
+repos:
+  github.com/a/a:
+    name: github.com/a/a
+    url: git@github.com:a/a.git
+    pin: false
+    sha: ''
+"
+        );
+    }
+
+    const LIST: &str = "\
+metadata:
+  version: v1beta
+repos:
+# The tool itself.
+- name: github.com/mccurdyc/gitrs
+  pin: false
+- name: github.com/team/b
+  pin: true
+  sha: abc
+";
+
+    #[test]
+    fn test_list() {
+        let mut d = Document::parse(LIST);
+        assert_eq!(d.to_string(), LIST);
+
+        d.upsert("github.com/team/a", &value("github.com/team/a", false))
+            .expect("upsert failed");
+        d.upsert("github.com/team/b", &value("github.com/team/b", false))
+            .expect("upsert failed");
+        assert!(d
+            .remove("github.com/mccurdyc/gitrs")
+            .expect("remove failed"));
+
+        assert_eq!(
+            d.to_string(),
+            "\
+metadata:
+  version: v1beta
+repos:
+- name: github.com/team/a
+  url: git@github.com:team/a.git
+  pin: false
+  sha: ''
+- name: github.com/team/b
+  url: git@github.com:team/b.git
+  pin: false
+  sha: ''
+"
+        );
+
+        let mut d = Document::parse("repos: []\n");
+        d.upsert("github.com/a/a", &value("github.com/a/a", false))
+            .expect("upsert failed");
+        assert!(d
+            .to_string()
+            .starts_with("repos:\n- name: github.com/a/a\n"));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let mut d = Document::parse(CONFIG);
+        d.exclude("github.com/team/[*]").expect("exclude failed");
+        assert_eq!(
+            d.to_string(),
+            CONFIG.replace("\nrepos:\n", "\nexclude:\n- github.com/team/[*]\nrepos:\n")
+        );
+
+        let text = "\
+include:
+- team.yaml
+exclude:
+  # Not mine.
+  - github.com/team/c # archived
+repos: {}
+";
+        let mut d = Document::parse(text);
+        d.exclude("github.com/team/d").expect("exclude failed");
+        assert_eq!(
+            d.to_string(),
+            text.replace("# archived\n", "# archived\n  - github.com/team/d\n")
+        );
+
+        let mut d = Document::parse("exclude: []\nrepos: {}\n");
+        d.exclude("a").expect("exclude failed");
+        assert_eq!(d.to_string(), "exclude:\n- a\nrepos: {}\n");
+
+        // Values that need it are quoted.
+        let mut d = Document::parse("repos: {}\n");
+        d.exclude("[a]*").expect("exclude failed");
+        assert_eq!(d.to_string(), "exclude:\n- '[a]*'\nrepos: {}\n");
+
+        let mut d = Document::parse("exclude: [a]\nrepos: {}\n");
+        assert!(d.exclude("b").is_err());
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut d = Document::parse("repos: {github.com/a/a: {}}\n");
+        assert!(d.remove("github.com/a/a").is_err());
+
+        let mut d = Document::parse("metadata: {}\n");
+        assert!(d.remove("github.com/a/a").is_err());
+    }
+}
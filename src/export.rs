@@ -0,0 +1,283 @@
+use crate::repo::Repo;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Write};
+
+/// Format is a format `export` can write the repos in.
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// A shell script that clones each repo under the current directory.
+    Script,
+    Json,
+    Csv,
+    /// A myrepos `.mrconfig` meant to live in the root.
+    Mrconfig,
+    /// A manifest for Google's `repo` tool.
+    Repo,
+}
+
+/// Export is the result of the `export` command: the repos written in a
+/// format.
+#[derive(Serialize, Debug)]
+pub struct Export {
+    format: Format,
+    content: String,
+}
+
+impl fmt::Display for Export {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.content)
+    }
+}
+
+// Row is a repo with every field, for formats without optional fields.
+#[derive(Serialize)]
+struct Row<'a> {
+    name: &'a str,
+    url: &'a str,
+    pin: bool,
+    sha: &'a str,
+    branch: &'a str,
+    tags: &'a [String],
+}
+
+impl<'a> From<&'a Repo> for Row<'a> {
+    fn from(r: &'a Repo) -> Self {
+        Row {
+            name: r.get_name(),
+            url: r.get_url(),
+            pin: r.get_pin(),
+            sha: r.get_sha(),
+            branch: r.get_branch(),
+            tags: r.get_tags(),
+        }
+    }
+}
+
+/// export writes the repos, sorted by name, in format.
+pub fn export(repos: &HashMap<String, Repo>, format: Format) -> Result<Export> {
+    let mut sorted: Vec<&Repo> = repos.values().collect();
+    sorted.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+    let content = match format {
+        Format::Script => script(&sorted),
+        Format::Json => {
+            let rows: Vec<Row> = sorted.iter().map(|r| Row::from(*r)).collect();
+            serde_json::to_string_pretty(&rows)? + "\n"
+        }
+        Format::Csv => csv(&sorted),
+        Format::Mrconfig => mrconfig(&sorted),
+        Format::Repo => repo_manifest(&sorted),
+    };
+    Ok(Export { format, content })
+}
+
+// quote quotes s for a POSIX shell.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+// clone is the command that clones r into dir, checking out its branch or,
+// when it's pinned, its sha.
+fn clone(r: &Repo, dir: &str) -> String {
+    let mut cmd = String::from("git clone");
+    if !r.get_branch().is_empty() {
+        let _ = write!(cmd, " --branch {}", quote(r.get_branch()));
+    }
+    let _ = write!(cmd, " {} {}", quote(r.get_url()), quote(dir));
+    if r.get_pin() && !r.get_sha().is_empty() {
+        let _ = write!(
+            cmd,
+            " && git -C {} checkout --quiet --detach {}",
+            quote(dir),
+            quote(r.get_sha())
+        );
+    }
+    cmd
+}
+
+fn script(repos: &[&Repo]) -> String {
+    let mut s = String::from(
+        "#!/bin/sh\n# Clones the repos managed by gitrs under the current directory.\nset -eu\n",
+    );
+    for r in repos {
+        s.push('\n');
+        if !r.get_tags().is_empty() {
+            let _ = writeln!(s, "# tags: {}", r.get_tags().join(", "));
+        }
+        let _ = writeln!(
+            s,
+            "[ -e {} ] || {{ {}; }}",
+            quote(r.get_name()),
+            clone(r, r.get_name())
+        );
+    }
+    s
+}
+
+// csv writes RFC 4180 CSV, with tags separated by semicolons.
+fn csv(repos: &[&Repo]) -> String {
+    let field = |v: &str| match v.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", v.replace('"', "\"\"")),
+        false => v.to_owned(),
+    };
+
+    let mut s = String::from("name,url,pin,sha,branch,tags\r\n");
+    for r in repos {
+        let row = [
+            field(r.get_name()),
+            field(r.get_url()),
+            r.get_pin().to_string(),
+            field(r.get_sha()),
+            field(r.get_branch()),
+            field(&r.get_tags().join(";")),
+        ];
+        let _ = write!(s, "{}\r\n", row.join(","));
+    }
+    s
+}
+
+// mrconfig writes a section per repo. mr runs checkout in the repo's parent
+// directory, and has no notion of tags, so they're kept as comments.
+fn mrconfig(repos: &[&Repo]) -> String {
+    let mut s = String::from("# Generated by gitrs. Paths are relative to the root.\n");
+    for r in repos {
+        let dir = r.get_name().rsplit('/').next().unwrap_or_default();
+        let _ = write!(s, "\n[{}]\n", r.get_name());
+        if !r.get_tags().is_empty() {
+            let _ = writeln!(s, "# tags: {}", r.get_tags().join(", "));
+        }
+        let _ = writeln!(s, "checkout = {}", clone(r, dir));
+    }
+    s
+}
+
+// xml escapes s for use in an XML attribute.
+fn xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// repo_manifest writes a manifest with a remote per host and a project per
+// repo, at its gitrs path. Pins become the project's revision, otherwise its
+// branch, and tags become its groups.
+fn repo_manifest(repos: &[&Repo]) -> String {
+    let hosts: BTreeSet<&str> = repos.iter().map(|r| r.get_host()).collect();
+
+    let mut s = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<manifest>\n");
+    for h in hosts {
+        let _ = writeln!(
+            s,
+            "  <remote name=\"{}\" fetch=\"ssh://git@{}/\" />",
+            xml(h),
+            xml(h)
+        );
+    }
+
+    for r in repos {
+        let name = r
+            .get_name()
+            .split_once('/')
+            .map(|(_, n)| n)
+            .unwrap_or_default();
+        let _ = write!(
+            s,
+            "  <project name=\"{}\" path=\"{}\" remote=\"{}\"",
+            xml(name),
+            xml(r.get_name()),
+            xml(r.get_host())
+        );
+
+        let revision = match (r.get_pin(), r.get_sha(), r.get_branch()) {
+            (true, sha, _) if !sha.is_empty() => sha,
+            (_, _, branch) => branch,
+        };
+        if !revision.is_empty() {
+            let _ = write!(s, " revision=\"{}\"", xml(revision));
+        }
+        if !r.get_tags().is_empty() {
+            let _ = write!(s, " groups=\"{}\"", xml(&r.get_tags().join(",")));
+        }
+        s.push_str(" />\n");
+    }
+    s.push_str("</manifest>\n");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repos() -> HashMap<String, Repo> {
+        let a = Repo::new()
+            .name("github.com/a/a".to_string())
+            .unwrap()
+            .pin(true)
+            .sha("0123abc".to_string())
+            .to_owned();
+        let b = Repo::new()
+            .name("gitlab.com/b/it's".to_string())
+            .unwrap()
+            .branch("dev".to_string())
+            .tags(vec!["oncall".to_string(), "web".to_string()])
+            .unwrap()
+            .to_owned();
+        HashMap::from([(b.get_name().to_string(), b), (a.get_name().to_string(), a)])
+    }
+
+    #[test]
+    fn test_script() {
+        let got = export(&repos(), Format::Script).unwrap().to_string();
+        assert!(got.contains(
+            "[ -e 'github.com/a/a' ] || { git clone 'git@github.com:a/a.git' 'github.com/a/a' && git -C 'github.com/a/a' checkout --quiet --detach '0123abc'; }\n"
+        ));
+        assert!(got.contains(
+            "# tags: oncall, web\n[ -e 'gitlab.com/b/it'\\''s' ] || { git clone --branch 'dev' "
+        ));
+    }
+
+    #[test]
+    fn test_json() {
+        let got = export(&repos(), Format::Json).unwrap().to_string();
+        let v: serde_json::Value = serde_json::from_str(&got).unwrap();
+        assert_eq!(v[0]["name"], "github.com/a/a");
+        assert_eq!(v[0]["branch"], "");
+        assert_eq!(v[1]["tags"], serde_json::json!(["oncall", "web"]));
+    }
+
+    #[test]
+    fn test_csv() {
+        let got = export(&repos(), Format::Csv).unwrap().to_string();
+        assert_eq!(
+            got,
+            "name,url,pin,sha,branch,tags\r\n\
+             github.com/a/a,git@github.com:a/a.git,true,0123abc,,\r\n\
+             gitlab.com/b/it's,git@gitlab.com:b/it's.git,false,,dev,oncall;web\r\n"
+        );
+    }
+
+    #[test]
+    fn test_mrconfig() {
+        let got = export(&repos(), Format::Mrconfig).unwrap().to_string();
+        assert!(got
+            .contains("\n[github.com/a/a]\ncheckout = git clone 'git@github.com:a/a.git' 'a' && "));
+    }
+
+    #[test]
+    fn test_repo_manifest() {
+        let got = export(&repos(), Format::Repo).unwrap().to_string();
+        assert!(got.contains("  <remote name=\"github.com\" fetch=\"ssh://git@github.com/\" />\n"));
+        assert!(got.contains(
+            "  <project name=\"a/a\" path=\"github.com/a/a\" remote=\"github.com\" revision=\"0123abc\" />\n"
+        ));
+        assert!(got.contains(
+            "  <project name=\"b/it&apos;s\" path=\"gitlab.com/b/it&apos;s\" remote=\"gitlab.com\" revision=\"dev\" groups=\"oncall,web\" />\n"
+        ));
+    }
+}
@@ -0,0 +1,593 @@
+use crate::fs::expand;
+use crate::import::{self, Found, Unmapped};
+use crate::repo;
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use git2::Repository;
+use serde_yaml::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Tool is another multi-repo tool whose manifest or layout can be imported.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    /// myrepos' `.mrconfig`.
+    Mrconfig,
+    /// git-workspace's `workspace-lock.toml` or `workspace.toml`.
+    GitWorkspace,
+    /// gita's `repos.csv`.
+    Gita,
+    /// garden's `garden.yaml`.
+    Garden,
+    /// A ghq root, laid out as `<host>/<org>/<repo>`.
+    Ghq,
+}
+
+impl Tool {
+    /// default_path is where the tool keeps its manifest or repos by default.
+    pub fn default_path(&self) -> Result<PathBuf> {
+        let p = match self {
+            Tool::Mrconfig => "~/.mrconfig",
+            Tool::GitWorkspace => "${GIT_WORKSPACE}/workspace-lock.toml",
+            Tool::Gita => match env::var("XDG_CONFIG_HOME") {
+                Ok(_) => "${XDG_CONFIG_HOME}/gita/repos.csv",
+                Err(_) => "~/.config/gita/repos.csv",
+            },
+            Tool::Garden => "garden.yaml",
+            Tool::Ghq => "~/ghq",
+        };
+        expand(Path::new(p))
+    }
+}
+
+/// scan reads the repos another tool manages from its manifest, or its root
+/// for ghq. Entries gitrs can't represent are returned as unmapped.
+pub fn scan(tool: Tool, p: &Path) -> Result<(Vec<Found>, Vec<Unmapped>)> {
+    if tool == Tool::Ghq {
+        return ghq(p);
+    }
+
+    let text = fs::read_to_string(p).with_context(|| format!("failed to read {:?}", p))?;
+    // Relative paths in a manifest are relative to the manifest.
+    let dir = p.parent().unwrap_or(Path::new("."));
+
+    let entries = match tool {
+        Tool::Mrconfig => mrconfig(&text, dir),
+        Tool::GitWorkspace => git_workspace(&text),
+        Tool::Gita => return gita(&text),
+        Tool::Garden => garden(&text, dir)?,
+        Tool::Ghq => unreachable!("handled above"),
+    };
+
+    let mut found = Vec::new();
+    let mut unmapped = Vec::new();
+    for e in entries {
+        match e.resolve() {
+            Ok(f) => found.push(f),
+            Err((path, reason)) => unmapped.push(Unmapped::new(path, reason)),
+        }
+    }
+    Ok((found, unmapped))
+}
+
+// Entry is a repo as another tool's manifest describes it.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Entry {
+    /// path is where the tool clones the repo, or the entry's name.
+    path: PathBuf,
+    url: Option<String>,
+    branch: Option<String>,
+    ignored: Vec<String>,
+    /// problem is why the entry can't be represented, if it can't.
+    problem: Option<String>,
+}
+
+impl Entry {
+    fn new(path: PathBuf) -> Self {
+        Entry {
+            path,
+            ..Default::default()
+        }
+    }
+
+    fn resolve(self) -> std::result::Result<Found, (PathBuf, String)> {
+        if let Some(p) = self.problem {
+            return Err((self.path, p));
+        }
+        let url = match &self.url {
+            Some(u) => u,
+            None => return Err((self.path, "no url".to_owned())),
+        };
+
+        let found = repo::name_from_url(url)
+            .and_then(|n| Found::new(n, self.path.clone(), self.branch.clone(), None));
+        match found {
+            Ok(mut f) => {
+                for i in self.ignored {
+                    f.ignore(i);
+                }
+                Ok(f)
+            }
+            Err(e) => Err((self.path, format!("{:#}", e))),
+        }
+    }
+}
+
+// mrconfig reads myrepos' INI-style config, where each section is a repo's
+// path and its `checkout` command clones it.
+fn mrconfig(text: &str, dir: &Path) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut key: Option<String> = None;
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+
+        if let Some(section) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            key = None;
+            if section != "DEFAULT" {
+                entries.push(Entry::new(dir.join(section)));
+            }
+            continue;
+        }
+
+        let e = match entries.last_mut() {
+            Some(e) => e,
+            None => continue,
+        };
+
+        // Indented lines continue the previous value.
+        if line.starts_with(char::is_whitespace) {
+            if key.as_deref() == Some("checkout") {
+                e.problem = Some("multi-line checkout commands aren't supported".to_owned());
+            }
+            continue;
+        }
+
+        let (k, v) = match trimmed.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => continue,
+        };
+        key = Some(k.to_owned());
+        match k {
+            "checkout" => match git_clone(v) {
+                Ok((url, branch)) => {
+                    e.url = Some(url);
+                    e.branch = branch;
+                }
+                Err(err) => e.problem = Some(err.to_string()),
+            },
+            _ => e.ignored.push(format!("mr {} command", k)),
+        }
+    }
+
+    for e in entries.iter_mut() {
+        if e.url.is_none() && e.problem.is_none() {
+            e.problem = Some("no checkout command".to_owned());
+        }
+    }
+    entries
+}
+
+// git_clone extracts the url and branch from a `git clone` command line.
+fn git_clone(cmd: &str) -> Result<(String, Option<String>)> {
+    // Options of git clone that take a value.
+    const VALUED: &[&str] = &[
+        "-b",
+        "--branch",
+        "-o",
+        "--origin",
+        "--depth",
+        "-c",
+        "--config",
+        "--reference",
+        "--separate-git-dir",
+        "-u",
+        "--upload-pack",
+        "-j",
+        "--jobs",
+        "--template",
+        "--filter",
+    ];
+
+    let words = shell_words(cmd);
+    let mut it = words
+        .iter()
+        .map(|s| s.as_str())
+        .take_while(|w| !matches!(*w, "&&" | ";" | "||" | "|"));
+    if (it.next(), it.next()) != (Some("git"), Some("clone")) {
+        return Err(anyhow!("checkout isn't a git clone: {}", cmd));
+    }
+
+    let mut branch = None;
+    while let Some(w) = it.next() {
+        if let Some(b) = w.strip_prefix("--branch=") {
+            branch = Some(b.to_owned());
+        } else if VALUED.contains(&w) {
+            let v = it.next().map(|v| v.to_owned());
+            if matches!(w, "-b" | "--branch") {
+                branch = v;
+            }
+        } else if !w.starts_with('-') {
+            return Ok((w.to_owned(), branch));
+        }
+    }
+    Err(anyhow!("checkout doesn't clone a url: {}", cmd))
+}
+
+// shell_words splits a command line into words, honoring quotes.
+fn shell_words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+
+    for c in s.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+// git_workspace reads git-workspace's lock file, which lists each repo, or
+// its workspace file, whose providers can't be represented. Only the subset
+// of TOML these files use is understood.
+fn git_workspace(text: &str) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut provider: Option<(String, String)> = None;
+    let mut in_provider = false;
+
+    let flush = |provider: &mut Option<(String, String)>, entries: &mut Vec<Entry>| {
+        if let Some((kind, name)) = provider.take() {
+            let mut e = Entry::new(PathBuf::from(format!("provider {}/{}", kind, name)));
+            e.problem = Some(
+                "providers clone every repo of a user or org; run `git-workspace update` and import workspace-lock.toml instead".to_owned(),
+            );
+            entries.push(e);
+        }
+    };
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            flush(&mut provider, &mut entries);
+            in_provider = line == "[[provider]]";
+            if in_provider {
+                provider = Some((String::new(), String::new()));
+            } else if line == "[[repo]]" {
+                entries.push(Entry::new(PathBuf::new()));
+            }
+            continue;
+        }
+
+        let (k, v) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), toml_string(v.trim())),
+            None => continue,
+        };
+
+        if in_provider {
+            if let Some(p) = provider.as_mut() {
+                match k {
+                    "provider" => p.0 = v,
+                    "name" => p.1 = v,
+                    _ => {}
+                }
+            }
+            continue;
+        }
+
+        if let Some(e) = entries.last_mut() {
+            match k {
+                "path" => e.path = PathBuf::from(v),
+                "url" => e.url = Some(v),
+                "branch" => e.branch = Some(v),
+                "upstream" => e.ignored.push(format!("upstream remote {}", v)),
+                _ => {}
+            }
+        }
+    }
+    flush(&mut provider, &mut entries);
+    entries
+}
+
+// toml_string unquotes a basic or literal TOML string, leaving other values
+// as they are.
+fn toml_string(v: &str) -> String {
+    let v = match v.find(" #") {
+        Some(i) if !v.starts_with(['"', '\'']) => &v[..i],
+        _ => v,
+    };
+    for q in ['"', '\''] {
+        if let Some(s) = v.strip_prefix(q).and_then(|s| s.split(q).next()) {
+            return s.to_owned();
+        }
+    }
+    v.trim().to_owned()
+}
+
+// gita reads gita's repos.csv, where each line starts with a local clone's
+// path. gita doesn't record urls, so they're read from the clones.
+fn gita(text: &str) -> Result<(Vec<Found>, Vec<Unmapped>)> {
+    let mut found = Vec::new();
+    let mut unmapped = Vec::new();
+
+    for line in text.lines() {
+        let path = line.split(',').next().unwrap_or_default().trim();
+        if path.is_empty() {
+            continue;
+        }
+        let p = expand(Path::new(path))?;
+        match import::inspect(&p, false) {
+            Ok(f) => found.push(f),
+            Err(e) => unmapped.push(Unmapped::new(p, format!("{:#}", e))),
+        }
+    }
+    Ok((found, unmapped))
+}
+
+// garden reads the trees in a garden.yaml. A tree is either a url or a
+// mapping with a url, or an origin remote, and optionally a branch.
+fn garden(text: &str, dir: &Path) -> Result<Vec<Entry>> {
+    let v: Value = serde_yaml::from_str(text)?;
+    let trees = match v.get("trees") {
+        Some(Value::Mapping(m)) => m,
+        _ => return Err(anyhow!("no trees")),
+    };
+
+    let mut entries = Vec::new();
+    for (name, tree) in trees {
+        let name = name.as_str().unwrap_or_default();
+        let mut e = Entry::new(dir.join(name));
+
+        match tree {
+            Value::String(url) => e.url = Some(url.clone()),
+            Value::Mapping(m) => {
+                if let Some(p) = m.get("path").and_then(|p| p.as_str()) {
+                    e.path = dir.join(p);
+                }
+                e.url = m
+                    .get("url")
+                    .or_else(|| m.get("remotes").and_then(|r| r.get("origin")))
+                    .and_then(|u| u.as_str())
+                    .map(|u| u.to_owned());
+                e.branch = m
+                    .get("branch")
+                    .and_then(|b| b.as_str())
+                    .map(|b| b.to_owned());
+
+                for (k, v) in m {
+                    match k.as_str().unwrap_or_default() {
+                        "url" | "path" | "branch" => {}
+                        "remotes" => {
+                            for r in v.as_mapping().into_iter().flat_map(|r| r.keys()) {
+                                if r.as_str() != Some("origin") {
+                                    e.ignored
+                                        .push(format!("remote {}", r.as_str().unwrap_or_default()));
+                                }
+                            }
+                        }
+                        "symlink" => e.problem = Some("symlink trees aren't repos".to_owned()),
+                        "templates" if !m.contains_key("url") => {
+                            e.problem = Some("trees from templates aren't supported".to_owned())
+                        }
+                        k => e.ignored.push(format!("garden {}", k)),
+                    }
+                }
+            }
+            _ => e.problem = Some("tree should be a url or a mapping".to_owned()),
+        }
+
+        if e.url.as_deref().is_some_and(|u| u.contains("${")) {
+            e.problem = Some("urls with garden variables aren't supported".to_owned());
+        }
+        entries.push(e);
+    }
+    Ok(entries)
+}
+
+// ghq reads a ghq root, where repos are laid out as <host>/<org>/<repo>.
+fn ghq(root: &Path) -> Result<(Vec<Found>, Vec<Unmapped>)> {
+    if !root.is_dir() {
+        return Err(anyhow!("{:?} isn't a directory", root));
+    }
+
+    let mut found = Vec::new();
+    let mut unmapped = Vec::new();
+
+    let mut it = WalkDir::new(root)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            e.file_type().is_dir() && !e.file_name().to_string_lossy().starts_with('.')
+        });
+
+    while let Some(entry) = it.next() {
+        let e = entry?;
+        let p = e.path();
+        if !p.join(".git").exists() {
+            continue;
+        }
+        it.skip_current_dir();
+
+        let name = p.strip_prefix(root)?.to_string_lossy().to_string();
+        let res = Repository::open(p)
+            .map_err(anyhow::Error::from)
+            .and_then(|git| import::head(&git, false))
+            .and_then(|(branch, _)| Found::new(name, p.to_path_buf(), branch, None));
+        match res {
+            Ok(f) => found.push(f),
+            Err(e) => unmapped.push(Unmapped::new(p.to_path_buf(), format!("{:#}", e))),
+        }
+    }
+    Ok((found, unmapped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn names(found: &[Found]) -> Vec<&str> {
+        found.iter().map(|f| f.repo().get_name()).collect()
+    }
+
+    #[test]
+    fn test_git_clone() {
+        assert_eq!(
+            git_clone("git clone 'git@github.com:a/a.git' 'a'").unwrap(),
+            ("git@github.com:a/a.git".to_string(), None)
+        );
+        assert_eq!(
+            git_clone("git clone --depth 1 -b dev \"https://github.com/a/a\" a && cd a").unwrap(),
+            (
+                "https://github.com/a/a".to_string(),
+                Some("dev".to_string())
+            )
+        );
+        assert!(git_clone("svn co https://svn.example.com/a a").is_err());
+    }
+
+    #[test]
+    fn test_mrconfig() {
+        let dir = tempdir().unwrap();
+        let p = dir.path().join(".mrconfig");
+        fs::write(
+            &p,
+            "\
+[DEFAULT]
+git_gc = git gc \"$@\"
+
+[src/gitrs]
+checkout = git clone 'git@github.com:mccurdyc/gitrs.git' 'gitrs'
+update = git pull --rebase
+
+[src/deep]
+checkout = git clone https://gitlab.com/group/sub/deep.git
+
+[src/svn]
+checkout = svn co https://svn.example.com/trunk svn
+",
+        )
+        .unwrap();
+
+        let (found, unmapped) = scan(Tool::Mrconfig, &p).expect("scan failed");
+        assert_eq!(names(&found), vec!["github.com/mccurdyc/gitrs"]);
+        assert_eq!(found[0].path(), dir.path().join("src/gitrs"));
+
+        let paths: Vec<&Path> = unmapped.iter().map(|u| u.path()).collect();
+        assert_eq!(
+            paths,
+            vec![dir.path().join("src/deep"), dir.path().join("src/svn")]
+        );
+    }
+
+    #[test]
+    fn test_git_workspace() {
+        let got = git_workspace(
+            "\
+[[repo]]
+path = \"github/mccurdyc/gitrs\"
+url = \"git@github.com:mccurdyc/gitrs.git\"
+branch = \"main\"
+upstream = \"git@github.com:upstream/gitrs.git\"
+
+[[provider]]
+provider = \"github\"
+name = \"mccurdyc\" # comment
+path = \"github\"
+",
+        );
+        assert_eq!(got.len(), 2);
+        assert_eq!(
+            got[0].url.as_deref(),
+            Some("git@github.com:mccurdyc/gitrs.git")
+        );
+        assert_eq!(got[0].branch.as_deref(), Some("main"));
+        assert_eq!(got[0].ignored.len(), 1);
+        assert_eq!(got[1].path, PathBuf::from("provider github/mccurdyc"));
+        assert!(got[1].problem.is_some());
+    }
+
+    #[test]
+    fn test_garden() {
+        let dir = tempdir().unwrap();
+        let got = garden(
+            "\
+trees:
+  gitrs:
+    url: git@github.com:mccurdyc/gitrs.git
+    branch: main
+    remotes:
+      upstream: git@github.com:upstream/gitrs.git
+    commands:
+      test: cargo test
+  short: https://github.com/a/short
+  vars:
+    url: ${github}/a/vars
+  link:
+    symlink: gitrs
+",
+            dir.path(),
+        )
+        .expect("garden failed");
+
+        let found: Vec<Found> = got.into_iter().filter_map(|e| e.resolve().ok()).collect();
+        assert_eq!(
+            names(&found),
+            vec!["github.com/mccurdyc/gitrs", "github.com/a/short"]
+        );
+        assert_eq!(found[0].repo().get_branch(), "main");
+    }
+
+    #[test]
+    fn test_ghq() {
+        let dir = tempdir().unwrap();
+        Repository::init(dir.path().join("github.com/a/a")).unwrap();
+        Repository::init(dir.path().join("gitlab.com/group/sub/b")).unwrap();
+
+        let (found, unmapped) = scan(Tool::Ghq, dir.path()).expect("scan failed");
+        assert_eq!(names(&found), vec!["github.com/a/a"]);
+        assert_eq!(unmapped.len(), 1);
+    }
+
+    #[test]
+    fn test_gita() {
+        let dir = tempdir().unwrap();
+        let a = dir.path().join("a");
+        Repository::init(&a)
+            .unwrap()
+            .remote("origin", "git@github.com:a/a.git")
+            .unwrap();
+        let p = dir.path().join("repos.csv");
+        fs::write(
+            &p,
+            format!(
+                "{},a,,\n{},missing,,\n",
+                a.display(),
+                dir.path().join("missing").display()
+            ),
+        )
+        .unwrap();
+
+        let (found, unmapped) = scan(Tool::Gita, &p).expect("scan failed");
+        assert_eq!(names(&found), vec!["github.com/a/a"]);
+        assert_eq!(unmapped.len(), 1);
+    }
+}
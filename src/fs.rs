@@ -1,63 +1,589 @@
 use crate::repo;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use fs2::FileExt;
 use git2::{Cred, RemoteCallbacks};
+use glob::Pattern;
 use home;
-use log::{debug, error};
+use log::{debug, error, info};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
 use std::{env, fs, path::Path, path::PathBuf};
 use walkdir::WalkDir;
 
 const GITRS_ROOT_DEFAULT: &str = "src";
+const LOCK_FILE: &str = ".gitrs.lock";
+const PARTIAL_SUFFIX: &str = ".gitrs-partial";
+// ARCHIVE_DIR is where `remove --sync --archive` moves repos. It's hidden, so
+// sync never treats it as an undeclared repo.
+const ARCHIVE_DIR: &str = ".gitrs-archive";
 
-pub fn sync(root: PathBuf, repos: &HashMap<String, repo::Repo>, clean_only: &bool) -> Result<()> {
-    sync_with_fn(root, repos, clean_only, clone_ssh)
+// CANCEL is set when gitrs is asked to shut down. In-flight clones are aborted
+// and no further repos are started.
+static CANCEL: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+
+/// SyncReport records what sync did to each repo.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    repos: Vec<SyncResult>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct SyncResult {
+    name: String,
+    action: SyncAction,
+    outcome: Outcome,
+    duration_ms: u128,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncAction {
+    Clone,
+    Remove,
+    Archive,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Failed(String),
+}
+
+impl SyncReport {
+    pub fn repos(&self) -> &Vec<SyncResult> {
+        &self.repos
+    }
+
+    /// failed returns the number of repos that could not be synced.
+    pub fn failed(&self) -> usize {
+        self.repos
+            .iter()
+            .filter(|r| matches!(r.outcome, Outcome::Failed(_)))
+            .count()
+    }
+
+    fn record(&mut self, name: &str, action: SyncAction, started: Instant, res: Result<()>) {
+        let outcome = match res {
+            Ok(_) => Outcome::Ok,
+            Err(e) => {
+                error!("Failed to {:?} {}: {:#}", action, name, e);
+                Outcome::Failed(format!("{:#}", e))
+            }
+        };
+
+        self.repos.push(SyncResult {
+            name: name.to_owned(),
+            action,
+            outcome,
+            duration_ms: started.elapsed().as_millis(),
+        });
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in &self.repos {
+            let action = match r.action {
+                SyncAction::Clone => "cloned",
+                SyncAction::Remove => "removed",
+                SyncAction::Archive => "archived",
+            };
+            match &r.outcome {
+                Outcome::Ok => writeln!(f, "{} {}", action, r.name)?,
+                Outcome::Failed(e) => writeln!(f, "failed {} {}: {}", action, r.name, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Synced is the result of a command that changed the config and then synced
+/// just the repos it changed e.g., `add --sync`.
+#[derive(Serialize, Debug)]
+pub struct Synced<'a, T: Serialize> {
+    #[serde(flatten)]
+    result: &'a T,
+    sync: SyncReport,
+}
+
+impl<'a, T: Serialize> Synced<'a, T> {
+    pub fn new(result: &'a T, sync: SyncReport) -> Self {
+        Synced { result, sync }
+    }
+}
+
+impl<T: Serialize + fmt::Display> fmt::Display for Synced<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.result.to_string().trim_end())?;
+        write!(f, "{}", self.sync)
+    }
+}
+
+/// Guard refuses to remove a suspicious number of repos e.g., because the
+/// config was accidentally emptied, unless mass deletion is allowed.
+#[derive(Debug, Clone)]
+pub struct Guard {
+    max_delete: usize,
+    max_delete_percent: usize,
+    allow_mass_delete: bool,
+    sanity: Option<String>,
+}
+
+impl Guard {
+    pub fn new() -> Self {
+        Guard {
+            max_delete: 25,
+            max_delete_percent: 50,
+            allow_mass_delete: false,
+            sanity: None,
+        }
+    }
+
+    /// max_delete is the maximum number of repos that may be removed at once.
+    pub fn max_delete(&mut self, n: usize) -> &mut Self {
+        self.max_delete = n;
+        self
+    }
+
+    /// max_delete_percent is the maximum percentage of the repos on disk that
+    /// may be removed at once. Removing a single repo is always allowed, so
+    /// that small roots aren't stuck behind the percentage.
+    pub fn max_delete_percent(&mut self, p: usize) -> &mut Self {
+        self.max_delete_percent = p;
+        self
+    }
+
+    pub fn allow_mass_delete(&mut self, allow: bool) -> &mut Self {
+        self.allow_mass_delete = allow;
+        self
+    }
+
+    /// sanity is the reason the config failed its sanity check, if it did.
+    pub fn sanity(&mut self, sanity: Option<String>) -> &mut Self {
+        self.sanity = sanity;
+        self
+    }
+
+    /// check returns an error listing the repos that would be removed if
+    /// removing them looks like a mistake.
+    pub fn check(&self, root: &Path, declared: usize, removed: &[String]) -> Result<()> {
+        let removed: Vec<&String> = removed.iter().filter(|n| root.join(n).exists()).collect();
+        if self.allow_mass_delete || removed.is_empty() {
+            return Ok(());
+        }
+
+        let existing = repo_dirs(root)?.len();
+        let reason = if let Some(s) = &self.sanity {
+            format!("the config failed a sanity check: {}", s)
+        } else if declared == 0 {
+            "the config has no repos".to_owned()
+        } else if removed.len() > self.max_delete {
+            format!("more than {} repos would be removed", self.max_delete)
+        } else if removed.len() > 1 && removed.len() * 100 > existing * self.max_delete_percent {
+            format!(
+                "more than {}% of repos would be removed",
+                self.max_delete_percent
+            )
+        } else {
+            return Ok(());
+        };
+
+        let list: Vec<String> = removed.iter().map(|n| format!("  {}", n)).collect();
+        Err(anyhow!(
+            "refusing to remove {} of {} repos because {}; pass --allow-mass-delete to remove:\n{}",
+            removed.len(),
+            existing,
+            reason,
+            list.join("\n")
+        ))
+    }
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// sync clones the repos in the config and removes the directories under root
+/// that aren't in it. When only isn't empty, just the repos and directories
+/// whose names match one of its globs are touched.
+pub fn sync(
+    root: PathBuf,
+    repos: &HashMap<String, repo::Repo>,
+    clean_only: &bool,
+    guard: &Guard,
+    only: &[Pattern],
+) -> Result<SyncReport> {
+    sync_with_fn(root, repos, clean_only, guard, only, clone_ssh)
 }
 
 fn sync_with_fn(
     root: PathBuf,
     repos: &HashMap<String, repo::Repo>,
     _clean_only: &bool,
+    guard: &Guard,
+    only: &[Pattern],
     clone_fn: fn(&str, &Path) -> Result<()>,
-) -> Result<()> {
-    for entry in WalkDir::new(root.as_path())
+) -> Result<SyncReport> {
+    let matches = |n: &str| only.is_empty() || only.iter().any(|p| p.matches(n));
+
+    // If the directory doesn't exist in the config, delete it.
+    // This forces you to declare the repos.
+    // TODO (mccurdyc): consider fetching updates for all repos here.
+    let removed: Vec<String> = undeclared(root.as_path(), repos)?
+        .into_iter()
+        .filter(|n| matches(n))
+        .collect();
+    guard.check(root.as_path(), repos.len(), &removed)?;
+
+    debug!("Looping repositories: {:?}", repos);
+
+    let mut added: Vec<&repo::Repo> = repos.values().filter(|r| matches(r.get_name())).collect();
+    added.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+    if !only.is_empty() && added.is_empty() && removed.is_empty() {
+        let globs: Vec<&str> = only.iter().map(|p| p.as_str()).collect();
+        return Err(anyhow!("no repos or directories match {:?}", globs));
+    }
+
+    Ok(apply_with_fn(root.as_path(), &added, &removed, clone_fn))
+}
+
+/// apply clones the added repos and removes the removed repos without walking
+/// the rest of the root.
+pub fn apply(root: &Path, added: &[&repo::Repo], removed: &[String]) -> SyncReport {
+    apply_with_fn(root, added, removed, clone_ssh)
+}
+
+fn apply_with_fn(
+    root: &Path,
+    added: &[&repo::Repo],
+    removed: &[String],
+    clone_fn: fn(&str, &Path) -> Result<()>,
+) -> SyncReport {
+    let mut report = SyncReport::default();
+
+    for s in removed {
+        if cancelled() {
+            return report;
+        }
+
+        let d = root.join(s);
+        if !d.exists() {
+            continue;
+        }
+
+        // TODO (mccurdyc): prompt for input if there are uncommitted changes.
+        let started = Instant::now();
+        // remove_dir_all removes a link itself rather than what it points to.
+        let res = contained(root, d.as_path())
+            .and_then(|d| fs::remove_dir_all(d).map_err(|e| anyhow!(e)));
+        report.record(s, SyncAction::Remove, started, res);
+    }
+
+    // If directory doesn't exist, clone it.
+    for r in added {
+        if cancelled() {
+            return report;
+        }
+
+        debug!("On repository: {:?}", r.get_name());
+
+        if !root.join(r.get_name()).exists() {
+            let started = Instant::now();
+            let res = contained(root, root.join(r.get_name()).as_path()).and_then(|d| {
+                clone_atomic(r.get_url(), d.as_path(), clone_fn)?;
+                match r.get_branch() {
+                    "" => Ok(()),
+                    b => checkout(d.as_path(), b)
+                        .with_context(|| format!("cloned, but failed to check out {}", b)),
+                }
+            });
+            report.record(r.get_name(), SyncAction::Clone, started, res);
+        }
+    }
+
+    report
+}
+
+/// archive moves the removed repos under the root's archive directory instead
+/// of deleting them, suffixed with the time so earlier archives are kept.
+pub fn archive(root: &Path, removed: &[String]) -> SyncReport {
+    let mut report = SyncReport::default();
+    let stamp = Local::now().format("%Y%m%dT%H%M%S");
+
+    for s in removed {
+        let d = root.join(s);
+        if !d.exists() {
+            continue;
+        }
+
+        let started = Instant::now();
+        // Move d as is, so that a link is archived rather than what it points to.
+        let res = contained(root, d.as_path()).and_then(|_| {
+            let dst = root.join(ARCHIVE_DIR).join(format!("{}.{}", s, stamp));
+            info!("Archiving {:?} to {:?}", d, dst);
+            fs::create_dir_all(dst.parent().unwrap_or(&dst))?;
+            fs::rename(&d, &dst).with_context(|| format!("failed to move {:?} to {:?}", d, dst))
+        });
+        report.record(s, SyncAction::Archive, started, res);
+    }
+
+    report
+}
+
+/// contained returns p if, with symlinks resolved, it's strictly inside root.
+/// Resolving symlinks prevents a link anywhere along p from escaping the root.
+/// p itself is returned unresolved, so that removing a link removes the link
+/// and not what it points to.
+pub fn contained(root: &Path, p: &Path) -> Result<PathBuf> {
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("failed to resolve root {:?}", root))?;
+
+    // Only the deepest existing ancestor can be resolved; the components
+    // after it don't exist yet, so they can't be links.
+    let mut existing = p;
+    let mut rest = Vec::new();
+    while existing.symlink_metadata().is_err() {
+        rest.push(
+            existing
+                .file_name()
+                .ok_or_else(|| anyhow!("invalid path {:?}", p))?,
+        );
+        existing = existing
+            .parent()
+            .ok_or_else(|| anyhow!("invalid path {:?}", p))?;
+    }
+
+    let mut resolved = existing
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {:?}", existing))?;
+    for c in rest.into_iter().rev() {
+        resolved.push(c);
+    }
+
+    if resolved == root || !resolved.starts_with(&root) {
+        return Err(anyhow!("{:?} is outside of the root {:?}", p, root));
+    }
+
+    Ok(p.to_path_buf())
+}
+
+/// prune removes dir, if it's empty, and then each of its empty parents up
+/// to, but not including, root. Returns the directories it removed.
+pub fn prune(root: &Path, dir: &Path) -> Vec<PathBuf> {
+    let mut removed = Vec::new();
+    let mut d = dir;
+    while d.starts_with(root) && d != root {
+        // remove_dir only removes empty directories.
+        if fs::remove_dir(d).is_err() {
+            break;
+        }
+        debug!("Removed empty directory: {:?}", d);
+        removed.push(d.to_path_buf());
+        d = match d.parent() {
+            Some(p) => p,
+            None => break,
+        };
+    }
+    removed
+}
+
+/// undeclared returns the names of the directories under root that aren't
+/// in the config i.e., the directories the next sync will remove.
+pub fn undeclared(root: &Path, repos: &HashMap<String, repo::Repo>) -> Result<Vec<String>> {
+    Ok(repo_dirs(root)?
+        .into_iter()
+        .filter(|n| !repos.contains_key(n))
+        .collect())
+}
+
+// repo_dirs returns the names of every <host>/<org>/<repo> directory under
+// root, sorted.
+fn repo_dirs(root: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+
+    for entry in WalkDir::new(root)
         .min_depth(3) // forces it to look at full paths only
         .max_depth(3)
         .contents_first(true)
     {
-        // If the directory doesn't exist in the config, delete it.
-        // This forces you to declare the repos.
         let e = entry?;
         let d = e.path();
-        let f = d.strip_prefix(root.as_path())?;
+        let f = d.strip_prefix(root)?;
+
+        // Hosts are never hidden, so hidden top-level directories e.g., the
+        // config's history repo, aren't repos.
+        if f.iter()
+            .next()
+            .is_some_and(|c| c.to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
         debug!("Using directory: {:?}", d);
 
-        // TODO (mccurdyc): consider fetching updates for all repos here.
         if let Some(s) = f.to_str() {
-            if !repos.contains_key(s) {
-                // TODO (mccurdyc): prompt for input if there are uncommitted changes.
-                fs::remove_dir_all(d)?;
-            }
+            names.push(s.to_owned());
         };
     }
 
-    debug!("Looping repositories: {:?}", repos);
+    names.sort();
+    Ok(names)
+}
 
-    // If directory doesn't exist, clone it.
-    for r in repos.values() {
-        debug!("On repository: {:?}", r.get_name());
+// clone_atomic clones into a temporary sibling of dst and renames it into
+// place, so an interrupted clone never leaves a half-cloned repo at dst. A
+// partial clone left behind by a crash is undeclared, so the next sync
+// removes it.
+fn clone_atomic(url: &str, dst: &Path, clone_fn: fn(&str, &Path) -> Result<()>) -> Result<()> {
+    let parent = dst
+        .parent()
+        .ok_or_else(|| anyhow!("repo path has no parent: {:?}", dst))?;
+    let name = dst
+        .file_name()
+        .ok_or_else(|| anyhow!("repo path has no name: {:?}", dst))?;
+    let tmp = parent.join(format!(".{}{}", name.to_string_lossy(), PARTIAL_SUFFIX));
 
-        if !root.join(r.get_name()).exists() {
-            clone_fn(r.get_url(), root.join(r.get_name()).as_path())?;
+    fs::create_dir_all(parent)?;
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+
+    match clone_fn(url, tmp.as_path()) {
+        Ok(_) => Ok(fs::rename(&tmp, dst)?),
+        Err(e) => {
+            if tmp.exists() {
+                fs::remove_dir_all(&tmp)?;
+            }
+            Err(e)
         }
     }
+}
+
+/// cancel_flag returns the flag that, once set, aborts in-flight clones and
+/// stops sync from starting any more repos. It's meant to be registered as a
+/// signal handler.
+pub fn cancel_flag() -> Arc<AtomicBool> {
+    Arc::clone(&CANCEL)
+}
+
+pub fn cancelled() -> bool {
+    CANCEL.load(Ordering::Relaxed)
+}
 
+/// lock takes an exclusive lock on root so that concurrent syncs don't race.
+/// Returns None when another process holds the lock. The lock is released
+/// when the returned file is dropped.
+pub fn lock(root: &Path) -> Result<Option<File>> {
+    let f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(root.join(LOCK_FILE))
+        .context("failed to open lock file")?;
+
+    match f.try_lock_exclusive() {
+        Ok(_) => Ok(Some(f)),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(None),
+        Err(e) => Err(anyhow!(e)),
+    }
+}
+
+/// write_atomic writes contents to a temporary file in the same directory as p,
+/// fsyncs it and renames it over p so that readers never see a partial write.
+pub fn write_atomic(p: &Path, contents: &[u8]) -> Result<()> {
+    let dir = p
+        .parent()
+        .ok_or_else(|| anyhow!("path has no parent: {:?}", p))?;
+
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(contents)?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(p)?;
+
+    info!("Wrote {:?}", p);
     Ok(())
 }
 
 // https://docs.rs/git2/latest/git2/build/struct.RepoBuilder.html
 fn clone_ssh(url: &str, dst: &Path) -> Result<()> {
+    // Prepare builder.
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.fetch_options(fetch_options());
+
+    // Clone the project.
+    debug!("Using clone url: {}", url);
+    match builder.clone(url, dst) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!(e)),
+    }
+}
+
+// checkout checks out a branch of a freshly cloned repo, tracking origin's.
+fn checkout(dst: &Path, branch: &str) -> Result<()> {
+    let repo = git2::Repository::open(dst)?;
+    let upstream = format!("origin/{}", branch);
+    let commit = repo
+        .find_branch(&upstream, git2::BranchType::Remote)?
+        .get()
+        .peel_to_commit()?;
+
+    if repo.find_branch(branch, git2::BranchType::Local).is_err() {
+        repo.branch(branch, &commit, false)?
+            .set_upstream(Some(&upstream))?;
+    }
+    repo.set_head(&format!("refs/heads/{}", branch))?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+    Ok(())
+}
+
+fn clone_bare(url: &str, dst: &Path) -> Result<()> {
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.bare(true).fetch_options(fetch_options());
+
+    debug!("Using clone url: {}", url);
+    builder.clone(url, dst)?;
+    Ok(())
+}
+
+/// fetch_bare clones url into dst as a bare repo or, if dst already exists,
+/// fetches its branches and tags. It uses the same transport as sync.
+pub fn fetch_bare(url: &str, dst: &Path) -> Result<git2::Repository> {
+    if !dst.exists() {
+        clone_atomic(url, dst, clone_bare)?;
+        return Ok(git2::Repository::open_bare(dst)?);
+    }
+
+    let repo = git2::Repository::open_bare(dst)?;
+    repo.remote_anonymous(url)?.fetch(
+        &[
+            "+refs/heads/*:refs/remotes/origin/*",
+            "+refs/tags/*:refs/tags/*",
+        ],
+        Some(&mut fetch_options()),
+        None,
+    )?;
+    Ok(repo)
+}
+
+// fetch_options authenticates with the SSH key from SSH_PRIVKEY_PATH and
+// aborts the transfer once gitrs is cancelled.
+fn fetch_options<'a>() -> git2::FetchOptions<'a> {
     let mut callbacks = RemoteCallbacks::new();
 
+    // Returning false aborts the transfer.
+    callbacks.transfer_progress(|_| !cancelled());
+
     callbacks.credentials(|_url, username, _allowed_types| {
         let mut ssh_privkey = PathBuf::new();
         let mut ssh_privkey_pass = String::from("");
@@ -83,7 +609,7 @@ fn clone_ssh(url: &str, dst: &Path) -> Result<()> {
         Cred::ssh_key(
             username.unwrap(),
             None,
-            &ssh_privkey.as_path(),
+            ssh_privkey.as_path(),
             Some(ssh_privkey_pass.as_str()),
         )
     });
@@ -91,17 +617,22 @@ fn clone_ssh(url: &str, dst: &Path) -> Result<()> {
     // Prepare fetch options.
     let mut fo = git2::FetchOptions::new();
     fo.remote_callbacks(callbacks);
+    fo
+}
 
-    // Prepare builder.
-    let mut builder = git2::build::RepoBuilder::new();
-    builder.fetch_options(fo);
+/// cache_dir returns the directory gitrs caches fetched data in,
+/// `$XDG_CACHE_HOME/gitrs`, defaulting to `$HOME/.cache/gitrs`.
+pub fn cache_dir() -> Result<PathBuf> {
+    cache_dir_with(|k| env::var(k).ok())
+}
 
-    // Clone the project.
-    debug!("Using clone url: {}", url);
-    match builder.clone(url, dst) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(anyhow!(e)),
-    }
+/// cache_dir_with is cache_dir with var in place of the environment.
+pub fn cache_dir_with(var: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    let base = match var("XDG_CACHE_HOME").filter(|x| !x.is_empty()) {
+        Some(x) => expand_with(Path::new(&x), &var)?,
+        None => expand_with(Path::new("~/.cache"), &var)?,
+    };
+    Ok(base.join("gitrs"))
 }
 
 pub fn init(p: Option<PathBuf>) -> Result<PathBuf> {
@@ -112,17 +643,79 @@ pub fn init(p: Option<PathBuf>) -> Result<PathBuf> {
     Ok(r.to_path_buf())
 }
 
-fn root(p: Option<PathBuf>) -> PathBuf {
+/// root returns p, or the default root when p is None.
+pub fn root(p: Option<PathBuf>) -> PathBuf {
     if let Some(r) = p {
         return r;
     }
 
     // defaults to $HOME/src
     let h = home::home_dir().expect("couldn't get user's HOME directory");
-    return h.join(PathBuf::from(GITRS_ROOT_DEFAULT));
+    h.join(PathBuf::from(GITRS_ROOT_DEFAULT))
+}
+
+/// expand expands a leading `~` to the home directory and `$VAR` or `${VAR}`
+/// to the variable's value. Unset variables are an error.
+pub fn expand(p: &Path) -> Result<PathBuf> {
+    expand_with(p, |k| env::var(k).ok())
+}
+
+/// expand_with is expand with var in place of the environment.
+pub fn expand_with(p: &Path, var: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    // Non-UTF-8 paths can't contain anything to expand that we'd recognize.
+    let s = match p.to_str() {
+        Some(s) => s,
+        None => return Ok(p.to_path_buf()),
+    };
+
+    let mut out = String::new();
+    let rest = match s == "~" || s.starts_with("~/") {
+        true => {
+            let h = var("HOME")
+                .map(PathBuf::from)
+                .or_else(home::home_dir)
+                .ok_or_else(|| anyhow!("couldn't get user's HOME directory"))?;
+            out.push_str(&h.to_string_lossy());
+            &s[1..]
+        }
+        false => s,
+    };
+
+    let mut i = 0;
+    while let Some(j) = rest[i..].find('$') {
+        out.push_str(&rest[i..i + j]);
+        let at = i + j + 1;
+
+        let (name, next) = match rest[at..].strip_prefix('{') {
+            Some(braced) => {
+                let end = braced
+                    .find('}')
+                    .ok_or_else(|| anyhow!("unclosed ${{ in {:?}", s))?;
+                (&braced[..end], at + end + 2)
+            }
+            None => {
+                let len = rest[at..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len() - at);
+                (&rest[at..at + len], at + len)
+            }
+        };
+
+        if name.is_empty() {
+            out.push('$');
+        } else {
+            let v = var(name).ok_or_else(|| anyhow!("{} isn't set, in {:?}", name, s))?;
+            out.push_str(&v);
+        }
+        i = next;
+    }
+    out.push_str(&rest[i..]);
+
+    Ok(PathBuf::from(out))
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     use repo;
@@ -182,6 +775,359 @@ mod tests {
         cleanup(root);
     }
 
+    #[test]
+    fn test_expand() {
+        let var = |k: &str| match k {
+            "HOME" => Some("/home/user".to_string()),
+            "WORK" => Some("/work".to_string()),
+            _ => None,
+        };
+
+        for (p, want) in [
+            ("~", "/home/user"),
+            ("~/src", "/home/user/src"),
+            ("$WORK/src", "/work/src"),
+            ("${WORK}src", "/worksrc"),
+            ("/a/~/b", "/a/~/b"),
+            ("/a$/b", "/a$/b"),
+            ("~/$WORK", "/home/user//work"),
+        ] {
+            let got = expand_with(Path::new(p), var).expect("expand failed");
+            assert_eq!(got, PathBuf::from(want), "expanding {:?}", p);
+        }
+
+        assert!(expand_with(Path::new("$UNSET/src"), var).is_err());
+        assert!(expand_with(Path::new("${WORK"), var).is_err());
+    }
+
+    fn fake_clone(_url: &str, dst: &Path) -> Result<()> {
+        fs::create_dir_all(dst)?;
+        Ok(())
+    }
+
+    fn failing_clone(url: &str, _dst: &Path) -> Result<()> {
+        Err(anyhow!("failed to clone {}", url))
+    }
+
+    fn test_repos(names: &[&str]) -> HashMap<String, repo::Repo> {
+        names
+            .iter()
+            .map(|n| {
+                (
+                    n.to_string(),
+                    repo::Repo::new()
+                        .name(n.to_string())
+                        .expect("name failed")
+                        .to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_report() {
+        let root = setup();
+        let p = root.path().to_path_buf();
+        fs::create_dir_all(p.join("github.com/old/old")).expect("failed to create dir");
+        // e.g., the config's history repo.
+        fs::create_dir_all(p.join(".gitrs-history/objects/ab")).expect("failed to create dir");
+
+        let got = sync_with_fn(
+            p.clone(),
+            &test_repos(&["github.com/a/a"]),
+            &false,
+            &Guard::new(),
+            &[],
+            fake_clone,
+        )
+        .expect("sync failed");
+
+        assert_eq!(got.failed(), 0);
+        assert_eq!(got.repos().len(), 2);
+        assert_eq!(got.repos()[0].name, "github.com/old/old");
+        assert_eq!(got.repos()[0].action, SyncAction::Remove);
+        assert_eq!(got.repos()[1].name, "github.com/a/a");
+        assert_eq!(got.repos()[1].action, SyncAction::Clone);
+        assert!(p.join("github.com/a/a").exists());
+        assert!(!p.join("github.com/old/old").exists());
+        assert!(p.join(".gitrs-history/objects/ab").exists());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_sync_only() {
+        let root = setup();
+        let p = root.path().to_path_buf();
+        fs::create_dir_all(p.join("github.com/acme/old")).expect("failed to create dir");
+        fs::create_dir_all(p.join("github.com/other/old")).expect("failed to create dir");
+        let repos = test_repos(&["github.com/acme/a", "github.com/other/b"]);
+        let mut guard = Guard::new();
+        guard.allow_mass_delete(true);
+
+        let only = [Pattern::new("github.com/acme/*").unwrap()];
+        let got = sync_with_fn(p.clone(), &repos, &false, &guard, &only, fake_clone)
+            .expect("sync failed");
+
+        let names: Vec<&str> = got.repos().iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["github.com/acme/old", "github.com/acme/a"]);
+        assert!(p.join("github.com/other/old").exists());
+        assert!(!p.join("github.com/other/b").exists());
+
+        let only = [Pattern::new("github.com/nobody/*").unwrap()];
+        assert!(sync_with_fn(p.clone(), &repos, &false, &guard, &only, fake_clone).is_err());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_checkout() {
+        let upstream = setup();
+        let dst = setup();
+
+        let repo = git2::Repository::init(upstream.path()).expect("failed to init");
+        let sig = git2::Signature::now("gitrs", "gitrs@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let oid = repo
+            .commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+            .expect("failed to commit");
+        repo.branch("dev", &repo.find_commit(oid).unwrap(), false)
+            .expect("failed to branch");
+
+        let p = dst.path().join("a");
+        git2::Repository::clone(upstream.path().to_str().unwrap(), &p).expect("failed to clone");
+        checkout(&p, "dev").expect("checkout failed");
+
+        let cloned = git2::Repository::open(&p).unwrap();
+        assert_eq!(cloned.head().unwrap().shorthand(), Some("dev"));
+        assert!(checkout(&p, "missing").is_err());
+    }
+
+    #[test]
+    fn test_sync_report_failure() {
+        let root = setup();
+        let p = root.path().to_path_buf();
+
+        let got = sync_with_fn(
+            p.clone(),
+            &test_repos(&["github.com/a/a", "github.com/b/b"]),
+            &false,
+            &Guard::new(),
+            &[],
+            failing_clone,
+        )
+        .expect("sync failed");
+
+        assert_eq!(got.failed(), 2);
+        assert_eq!(
+            got.repos()[0].outcome,
+            Outcome::Failed("failed to clone git@github.com:a/a.git".to_string())
+        );
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_contained() {
+        let root = setup();
+        let outside = setup();
+        let p = root.path();
+
+        fs::create_dir_all(p.join("github.com/a/a")).expect("failed to create dir");
+        let want = p.join("github.com/a/a");
+        assert_eq!(contained(p, want.as_path()).unwrap(), want);
+
+        // Doesn't exist yet.
+        let want = p.join("github.com/b/b");
+        assert_eq!(contained(p, want.as_path()).unwrap(), want);
+
+        // A symlink inside the root is returned as is, not resolved.
+        std::os::unix::fs::symlink(p.join("github.com/a/a"), p.join("github.com/a/link")).unwrap();
+        let want = p.join("github.com/a/link");
+        assert_eq!(contained(p, want.as_path()).unwrap(), want);
+
+        assert!(contained(p, p).is_err());
+        assert!(contained(p, p.join("../x").as_path()).is_err());
+        assert!(contained(p, outside.path()).is_err());
+
+        // A symlink that points outside of the root.
+        std::os::unix::fs::symlink(outside.path(), p.join("gitlab.com")).unwrap();
+        assert!(contained(p, p.join("gitlab.com/a/a").as_path()).is_err());
+
+        cleanup(root);
+        cleanup(outside);
+    }
+
+    #[test]
+    fn test_sync_removes_links_not_targets() {
+        let root = setup();
+        let p = root.path().to_path_buf();
+        fs::create_dir_all(p.join("github.com/a/a/.git")).expect("failed to create dir");
+        fs::create_dir_all(p.join("github.com/x")).expect("failed to create dir");
+        std::os::unix::fs::symlink(p.join("github.com/a/a"), p.join("github.com/x/link"))
+            .expect("failed to link");
+
+        let mut guard = Guard::new();
+        guard.allow_mass_delete(true);
+        let got = sync_with_fn(
+            p.clone(),
+            &test_repos(&["github.com/a/a"]),
+            &false,
+            &guard,
+            &[],
+            fake_clone,
+        )
+        .expect("sync failed");
+
+        assert_eq!(got.failed(), 0);
+        assert_eq!(got.repos()[0].name, "github.com/x/link");
+        assert!(!p.join("github.com/x/link").is_symlink());
+        assert!(p.join("github.com/a/a/.git").is_dir());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_archive() {
+        let root = setup();
+        let p = root.path().to_path_buf();
+        fs::create_dir_all(p.join("github.com/a/a/.git")).expect("failed to create dir");
+        fs::create_dir_all(p.join("github.com/b/b/.git")).expect("failed to create dir");
+        fs::create_dir_all(p.join("github.com/x")).expect("failed to create dir");
+        std::os::unix::fs::symlink(p.join("github.com/a/a"), p.join("github.com/x/link"))
+            .expect("failed to link");
+
+        let got = archive(
+            &p,
+            &[
+                "github.com/b/b".to_string(),
+                "github.com/x/link".to_string(),
+            ],
+        );
+        assert_eq!(got.failed(), 0);
+        assert_eq!(got.repos()[0].action, SyncAction::Archive);
+        assert!(!p.join("github.com/b/b").exists());
+        assert!(!p.join("github.com/x/link").is_symlink());
+        assert!(p.join("github.com/a/a/.git").is_dir());
+
+        let archived: Vec<PathBuf> = WalkDir::new(p.join(ARCHIVE_DIR))
+            .min_depth(3)
+            .max_depth(3)
+            .sort_by_file_name()
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .collect();
+        assert_eq!(archived.len(), 2);
+        assert!(archived[0].join(".git").is_dir());
+        assert!(archived[1].is_symlink());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_sync_doesnt_escape_root() {
+        let root = setup();
+        let outside = setup();
+        let p = root.path().to_path_buf();
+
+        fs::create_dir_all(outside.path().join("a/a")).expect("failed to create dir");
+        fs::create_dir_all(p.join("github.com/evil")).expect("failed to create dir");
+        std::os::unix::fs::symlink(outside.path().join("a/a"), p.join("github.com/evil/a"))
+            .unwrap();
+
+        // github.com/evil/a is undeclared, but it resolves outside of the root.
+        let got = sync_with_fn(
+            p.clone(),
+            &HashMap::new(),
+            &false,
+            Guard::new().allow_mass_delete(true),
+            &[],
+            fake_clone,
+        )
+        .expect("sync failed");
+        assert_eq!(got.failed(), 1);
+        assert!(outside.path().join("a/a").exists());
+
+        cleanup(root);
+        cleanup(outside);
+    }
+
+    #[test]
+    fn test_guard() {
+        let root = setup();
+        let p = root.path();
+
+        let names: Vec<String> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|n| format!("github.com/a/{}", n))
+            .collect();
+        for n in &names {
+            fs::create_dir_all(p.join(n)).expect("failed to create dir");
+        }
+
+        // Repos that aren't on disk don't count.
+        assert!(Guard::new()
+            .check(p, 0, &["github.com/x/x".to_string()])
+            .is_ok());
+
+        // Removing a single repo is fine.
+        assert!(Guard::new().check(p, 3, &names[..1]).is_ok());
+        assert!(Guard::new().check(p, 2, &names[..2]).is_ok());
+
+        // More than 50%.
+        assert!(Guard::new().check(p, 1, &names[..3]).is_err());
+        assert!(Guard::new()
+            .max_delete_percent(75)
+            .check(p, 1, &names[..3])
+            .is_ok());
+
+        // More than max_delete.
+        assert!(Guard::new().max_delete(1).check(p, 2, &names[..2]).is_err());
+
+        // The config has no repos.
+        let got = Guard::new().check(p, 0, &names[..1]);
+        assert!(got.is_err());
+        assert!(got
+            .unwrap_err()
+            .to_string()
+            .contains("pass --allow-mass-delete to remove:\n  github.com/a/a"));
+
+        // Failed sanity check.
+        assert!(Guard::new()
+            .sanity(Some("unknown key".to_string()))
+            .check(p, 3, &names[..1])
+            .is_err());
+
+        assert!(Guard::new()
+            .allow_mass_delete(true)
+            .check(p, 0, &names)
+            .is_ok());
+
+        cleanup(root);
+    }
+
+    #[test]
+    fn test_sync_refuses_mass_delete() {
+        let root = setup();
+        let p = root.path().to_path_buf();
+        fs::create_dir_all(p.join("github.com/a/a")).expect("failed to create dir");
+
+        let got = sync_with_fn(
+            p.clone(),
+            &HashMap::new(),
+            &false,
+            &Guard::new(),
+            &[],
+            fake_clone,
+        );
+        assert!(got.is_err());
+        assert!(p.join("github.com/a/a").exists());
+
+        cleanup(root);
+    }
+
     // #[test]
     // fn test_sync_add_repo_dir_doesnt_exists() {
     //     let root = setup();
@@ -0,0 +1,344 @@
+use crate::manifest;
+use anyhow::{anyhow, Context, Result};
+use chrono::{Local, TimeZone};
+use git2::{Commit, ErrorCode, FileMode, Oid, Repository, Signature};
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// OUTSIDE is the message changes made to the config file by something other
+/// than gitrs are committed with, before gitrs commits a change of its own.
+pub const OUTSIDE: &str = "update config outside of gitrs";
+
+/// History is the result of the `history` command, most recent change first.
+#[derive(Serialize, Debug, Default)]
+pub struct History {
+    changes: Vec<Change>,
+}
+
+/// Change is a commit that changed the config file.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    sha: String,
+    /// time is when the change was committed, in RFC 3339.
+    time: String,
+    message: String,
+    /// added and removed are the names of the config's own repos the change
+    /// added or removed. Included manifests aren't considered.
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl Change {
+    fn short(&self) -> &str {
+        &self.sha[..7]
+    }
+}
+
+impl fmt::Display for History {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in &self.changes {
+            writeln!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.short(), self.time, self.message)?;
+        for n in &self.added {
+            write!(f, "\n  + {}", n)?;
+        }
+        for n in &self.removed {
+            write!(f, "\n  - {}", n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Undo is the result of the `undo` command.
+#[derive(Serialize, Debug)]
+pub struct Undo {
+    undone: Change,
+}
+
+impl Undo {
+    pub fn new(undone: Change) -> Self {
+        Undo { undone }
+    }
+
+    /// message is the commit message for undoing a change.
+    pub fn message(undone: &Change) -> String {
+        format!("undo {}: {}", undone.short(), undone.message)
+    }
+}
+
+impl fmt::Display for Undo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "undid {}", self.undone)
+    }
+}
+
+// DIR is the bare repo, next to the config file, that its history is kept
+// in. It's separate from any repo the config's directory is already in, e.g.,
+// dotfiles, so that their index and HEAD are never touched, and bare, so that
+// the directory doesn't look like a work tree.
+const DIR: &str = ".gitrs-history";
+
+// open opens the history repo next to the config file, initializing it when
+// there isn't one yet.
+fn open(p: &Path, create: bool) -> Result<Repository> {
+    let dir = p
+        .parent()
+        .ok_or_else(|| anyhow!("config {:?} has no directory", p))?
+        .join(DIR);
+
+    match Repository::open_bare(&dir) {
+        Ok(r) => Ok(r),
+        Err(e) if create && e.code() == ErrorCode::NotFound => {
+            info!("Initializing config history in {:?}", dir);
+            Ok(Repository::init_bare(&dir)?)
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            Err(anyhow!("no config history in {:?} yet", dir))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn file_name(p: &Path) -> Result<&str> {
+    p.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("invalid config path {:?}", p))
+}
+
+/// commit commits the config file at p with message, returning the new
+/// commit's sha, or None when the file hasn't changed since the last commit.
+///
+/// The tree is built from the last commit's, with only the config file's
+/// entry replaced, so configs sharing a directory share the repo.
+pub fn commit(p: &Path, message: &str) -> Result<Option<String>> {
+    let repo = open(p, true)?;
+    let name = file_name(p)?;
+
+    let parent = head(&repo)?;
+    let base = match &parent {
+        Some(c) => Some(c.tree()?),
+        None => None,
+    };
+    let mut builder = repo.treebuilder(base.as_ref())?;
+    match p.exists() {
+        true => {
+            let blob = repo.blob(&fs::read(p)?)?;
+            builder.insert(name, blob, FileMode::Blob.into())?;
+        }
+        false if builder.get(name)?.is_some() => builder.remove(name)?,
+        false => {}
+    }
+    let tree = repo.find_tree(builder.write()?)?;
+
+    if parent.as_ref().is_some_and(|c| c.tree_id() == tree.id()) {
+        return Ok(None);
+    }
+    // Nothing to record e.g., a config that was never written.
+    if parent.is_none() && tree.is_empty() {
+        return Ok(None);
+    }
+
+    let sig = repo
+        .signature()
+        .or_else(|_| Signature::now("gitrs", "gitrs@localhost"))?;
+    let parents: Vec<&Commit> = parent.iter().collect();
+    let oid = repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)?;
+    debug!("Committed config {:?}: {}", p, message);
+
+    Ok(Some(oid.to_string()))
+}
+
+fn head(repo: &Repository) -> Result<Option<Commit<'_>>> {
+    match repo.head() {
+        Ok(h) => Ok(Some(h.peel_to_commit()?)),
+        Err(e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// history lists the commits that changed the config file at p, most recent
+/// first, up to limit of them.
+pub fn history(p: &Path, limit: Option<usize>) -> Result<History> {
+    let repo = open(p, false)?;
+    let changes = changes(&repo, file_name(p)?)?
+        .into_iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .map(|(c, _)| c)
+        .collect();
+    Ok(History { changes })
+}
+
+/// last returns the most recent change to the config file at p and the
+/// file's contents before it, which is None when the change created it.
+pub fn last(p: &Path) -> Result<(Change, Option<Vec<u8>>)> {
+    let repo = open(p, false)?;
+    let name = file_name(p)?;
+    let (change, before) = changes(&repo, name)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("the config has no history"))?;
+
+    let before = match before {
+        Some(oid) => Some(repo.find_blob(oid)?.content().to_vec()),
+        None => None,
+    };
+    Ok((change, before))
+}
+
+// changes walks the first-parent history of HEAD for commits that changed
+// the file, pairing each with the file's blob before it.
+fn changes(repo: &Repository, name: &str) -> Result<Vec<(Change, Option<Oid>)>> {
+    let mut out = Vec::new();
+    let mut next = head(repo)?;
+
+    while let Some(c) = next {
+        let parent = c.parents().next();
+        let after = blob(&c, name)?;
+        let before = match &parent {
+            Some(p) => blob(p, name)?,
+            None => None,
+        };
+
+        if after != before {
+            let (added, removed) = diff(repo, before, after)?;
+            let time = Local
+                .timestamp_opt(c.time().seconds(), 0)
+                .single()
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default();
+            out.push((
+                Change {
+                    sha: c.id().to_string(),
+                    time,
+                    message: c.summary().unwrap_or_default().to_owned(),
+                    added,
+                    removed,
+                },
+                before,
+            ));
+        }
+        next = parent;
+    }
+
+    Ok(out)
+}
+
+fn blob(c: &Commit, name: &str) -> Result<Option<Oid>> {
+    Ok(c.tree()?.get_name(name).map(|e| e.id()))
+}
+
+// diff returns the names of the repos added and removed between two versions
+// of the config file.
+fn diff(
+    repo: &Repository,
+    before: Option<Oid>,
+    after: Option<Oid>,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let before = names(repo, before)?;
+    let after = names(repo, after)?;
+    Ok((
+        after.difference(&before).cloned().collect(),
+        before.difference(&after).cloned().collect(),
+    ))
+}
+
+// names returns the names of the repos a version of the config file declares.
+// A version that doesn't parse declares none.
+fn names(repo: &Repository, blob: Option<Oid>) -> Result<BTreeSet<String>> {
+    let blob = match blob {
+        Some(b) => repo.find_blob(b)?,
+        None => return Ok(BTreeSet::new()),
+    };
+    let names = serde_yaml::from_slice(blob.content())
+        .context("invalid config")
+        .and_then(|v| manifest::Layer::declared(&v, ""))
+        .map(|l| l.entries().keys().cloned().collect())
+        .unwrap_or_default();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const A: &str = "metadata:\n  version: v1beta\n  root: /r\nrepos:\n  github.com/a/a: {}\n";
+    const AB: &str =
+        "metadata:\n  version: v1beta\n  root: /r\nrepos:\n  github.com/a/a: {}\n  github.com/b/b: {}\n";
+
+    #[test]
+    fn test_history() {
+        let dir = TempDir::new().unwrap();
+        let p = dir.path().join(".gitrs.yaml");
+
+        fs::write(&p, A).unwrap();
+        assert!(commit(&p, "create").unwrap().is_some());
+        assert_eq!(commit(&p, "nothing").unwrap(), None);
+        fs::write(&p, AB).unwrap();
+        assert!(commit(&p, "add github.com/b/b").unwrap().is_some());
+
+        let got = history(&p, None).unwrap();
+        assert_eq!(got.changes.len(), 2);
+        assert_eq!(got.changes[0].message, "add github.com/b/b");
+        assert_eq!(got.changes[0].added, vec!["github.com/b/b"]);
+        assert!(got.changes[0].removed.is_empty());
+        assert_eq!(got.changes[1].added, vec!["github.com/a/a"]);
+        assert_eq!(history(&p, Some(1)).unwrap().changes.len(), 1);
+
+        let (change, before) = last(&p).unwrap();
+        assert_eq!(change, got.changes[0]);
+        assert_eq!(before.unwrap(), A.as_bytes());
+
+        // A repo the config's directory is already in, e.g., dotfiles, isn't
+        // touched, and no repo is created in the directory itself.
+        assert!(!dir.path().join(".git").exists());
+        assert!(dir.path().join(DIR).join("HEAD").is_file());
+    }
+
+    #[test]
+    fn test_history_in_a_repo() {
+        let dir = TempDir::new().unwrap();
+        let p = dir.path().join(".gitrs.yaml");
+        let dotfiles = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("staged"), "x").unwrap();
+        let mut index = dotfiles.index().unwrap();
+        index.add_path(Path::new("staged")).unwrap();
+        index.write().unwrap();
+
+        fs::write(&p, A).unwrap();
+        assert!(commit(&p, "create").unwrap().is_some());
+
+        // The dotfiles' HEAD and index are as they were.
+        assert!(dotfiles.head().is_err());
+        let index = dotfiles.index().unwrap();
+        assert_eq!(index.len(), 1);
+        assert!(index.get_path(Path::new("staged"), 0).is_some());
+
+        // Only the config is in the history.
+        let repo = open(&p, false).unwrap();
+        let tree = head(&repo).unwrap().unwrap().tree().unwrap();
+        assert_eq!(tree.len(), 1);
+        assert!(tree.get_name(".gitrs.yaml").is_some());
+    }
+
+    #[test]
+    fn test_history_missing() {
+        let dir = TempDir::new().unwrap();
+        let p = dir.path().join(".gitrs.yaml");
+        assert!(history(&p, None).is_err());
+        assert!(!dir.path().join(DIR).exists());
+    }
+}
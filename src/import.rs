@@ -0,0 +1,284 @@
+use crate::repo::{self, Repo};
+use anyhow::{anyhow, Result};
+use git2::Repository;
+use log::debug;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Import is the result of the `import` command.
+#[derive(Serialize, Debug, Default)]
+pub struct Import {
+    /// added are the repos added to the config.
+    added: Vec<Found>,
+    /// existing are the repos found that the config already had.
+    existing: Vec<Found>,
+    /// unmapped are the git repos that couldn't be added.
+    unmapped: Vec<Unmapped>,
+    #[serde(skip)]
+    root: PathBuf,
+}
+
+/// Found is a git repo found under the imported directory.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    name: String,
+    path: PathBuf,
+    /// branch is the checked out branch. None when HEAD is detached.
+    branch: Option<String>,
+    /// sha is HEAD, when the repo was pinned to it.
+    sha: Option<String>,
+    /// ignored describes what the source said about the repo that gitrs
+    /// can't represent e.g., extra remotes.
+    ignored: Vec<String>,
+    #[serde(skip)]
+    repo: Repo,
+}
+
+impl Found {
+    /// new describes a repo found at path. The repo is pinned when sha is set.
+    pub fn new(
+        name: String,
+        path: PathBuf,
+        branch: Option<String>,
+        sha: Option<String>,
+    ) -> Result<Self> {
+        let r = Repo::new()
+            .name(name.clone())?
+            .pin(sha.is_some())
+            .sha(sha.clone().unwrap_or_default())
+            .branch(branch.clone().unwrap_or_default())
+            .to_owned();
+
+        Ok(Found {
+            name,
+            path,
+            branch,
+            sha,
+            ignored: Vec::new(),
+            repo: r,
+        })
+    }
+
+    /// ignore records something about the repo that gitrs can't represent.
+    pub fn ignore(&mut self, what: String) -> &mut Self {
+        self.ignored.push(what);
+        self
+    }
+
+    pub fn repo(&self) -> &Repo {
+        &self.repo
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Unmapped {
+    path: PathBuf,
+    reason: String,
+}
+
+impl Unmapped {
+    pub fn new(path: PathBuf, reason: String) -> Self {
+        Unmapped { path, reason }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Import {
+    /// new splits the repos found under a directory by whether the config
+    /// already had them.
+    pub fn new(root: &Path, found: Vec<Found>, unmapped: Vec<Unmapped>, added: &[String]) -> Self {
+        let (added, existing) = found.into_iter().partition(|f| added.contains(&f.name));
+        Import {
+            added,
+            existing,
+            unmapped,
+            root: root.to_path_buf(),
+        }
+    }
+}
+
+impl fmt::Display for Import {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in &self.added {
+            write!(f, "imported {}", r.name)?;
+            if let Some(b) = &r.branch {
+                write!(f, " on {}", b)?;
+            }
+            if let Some(s) = &r.sha {
+                write!(f, " pinned to {}", s)?;
+            }
+            // The next sync clones it to its canonical path.
+            if r.path != self.root.join(&r.name) {
+                write!(f, " from {}", r.path.display())?;
+            }
+            writeln!(f)?;
+            for i in &r.ignored {
+                writeln!(f, "  ignored {}", i)?;
+            }
+        }
+        for r in &self.existing {
+            writeln!(f, "{} is already managed", r.name)?;
+        }
+        for u in &self.unmapped {
+            writeln!(f, "couldn't import {}: {}", u.path.display(), u.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// scan walks dir for git repos and derives each one's name from its `origin`
+/// url. Hidden directories aren't searched and repos aren't searched for
+/// nested repos. When pin is true, repos are pinned to their HEAD.
+pub fn scan(dir: &Path, pin: bool) -> Result<(Vec<Found>, Vec<Unmapped>)> {
+    if !dir.is_dir() {
+        return Err(anyhow!("{:?} isn't a directory", dir));
+    }
+
+    let mut found: Vec<Found> = Vec::new();
+    let mut unmapped = Vec::new();
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+
+    let mut it = WalkDir::new(dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| {
+            e.file_type().is_dir() && !e.file_name().to_string_lossy().starts_with('.')
+        });
+
+    while let Some(entry) = it.next() {
+        let e = entry?;
+        let p = e.path();
+        if !p.join(".git").exists() {
+            continue;
+        }
+        it.skip_current_dir();
+
+        debug!("Found repo: {:?}", p);
+        match inspect(p, pin) {
+            Ok(f) => match seen.get(&f.name) {
+                Some(first) => unmapped.push(Unmapped {
+                    path: p.to_path_buf(),
+                    reason: format!("{} was already found at {}", f.name, first.display()),
+                }),
+                None => {
+                    seen.insert(f.name.clone(), p.to_path_buf());
+                    found.push(f);
+                }
+            },
+            Err(e) => unmapped.push(Unmapped {
+                path: p.to_path_buf(),
+                reason: format!("{:#}", e),
+            }),
+        }
+    }
+
+    Ok((found, unmapped))
+}
+
+/// inspect derives the repo at p from its `origin` url and current branch.
+pub fn inspect(p: &Path, pin: bool) -> Result<Found> {
+    let git = Repository::open(p)?;
+    let origin = git
+        .find_remote("origin")
+        .map_err(|_| anyhow!("no origin remote"))?;
+    let url = origin
+        .url()
+        .ok_or_else(|| anyhow!("origin url isn't valid UTF-8"))?;
+    let name = repo::name_from_url(url)?;
+
+    let (branch, sha) = head(&git, pin)?;
+    Found::new(name, p.to_path_buf(), branch, sha)
+}
+
+/// head returns the checked out branch of a repo, if any, and, when pin is
+/// true, the sha of HEAD.
+pub fn head(git: &Repository, pin: bool) -> Result<(Option<String>, Option<String>)> {
+    let head = git.head().ok();
+    let branch = head
+        .as_ref()
+        .filter(|h| h.is_branch())
+        .and_then(|h| h.shorthand())
+        .map(|b| b.to_owned());
+    let sha = match pin {
+        true => Some(
+            head.as_ref()
+                .and_then(|h| h.target())
+                .ok_or_else(|| anyhow!("can't pin a repo without commits"))?
+                .to_string(),
+        ),
+        false => None,
+    };
+    Ok((branch, sha))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn init(p: &Path, origin: Option<&str>) -> Repository {
+        let repo = Repository::init(p).expect("failed to init");
+        if let Some(o) = origin {
+            repo.remote("origin", o).expect("failed to add remote");
+        }
+        let sig = git2::Signature::now("gitrs", "gitrs@localhost").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+            .expect("failed to commit");
+        drop(tree);
+        repo
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let p = dir.path();
+
+        let a = init(&p.join("github.com/a/a"), Some("git@github.com:a/a.git"));
+        init(&p.join("work/b"), Some("https://gitlab.com/b/b.git"));
+        // Nested repos, e.g., submodules, aren't imported.
+        init(&p.join("work/b/vendor/c"), Some("git@github.com:c/c.git"));
+        init(&p.join("old/a"), Some("https://github.com/a/a"));
+        init(&p.join("local"), None);
+        init(&p.join(".hidden/d"), Some("git@github.com:d/d.git"));
+        fs::create_dir_all(p.join("empty")).unwrap();
+
+        let (found, unmapped) = scan(p, true).expect("scan failed");
+
+        let names: Vec<&str> = found.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["github.com/a/a", "gitlab.com/b/b"]);
+        assert_eq!(found[1].path, p.join("work/b"));
+        assert!(found[0].repo().get_pin());
+        assert_eq!(
+            found[0].sha,
+            Some(a.head().unwrap().target().unwrap().to_string())
+        );
+        assert_eq!(
+            found[0].repo().get_branch(),
+            found[0].branch.as_deref().unwrap()
+        );
+
+        let paths: Vec<&Path> = unmapped.iter().map(|u| u.path.as_path()).collect();
+        assert_eq!(paths, vec![p.join("local"), p.join("old/a")]);
+        assert_eq!(unmapped[0].reason, "no origin remote");
+        assert!(unmapped[1].reason.contains("already found"));
+
+        let (found, _) = scan(p, false).expect("scan failed");
+        assert_eq!(found[0].sha, None);
+        assert!(!found[0].repo().get_pin());
+    }
+}
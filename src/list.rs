@@ -0,0 +1,392 @@
+use crate::repo::Repo;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use glob::Pattern;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Column is a field of a repo that `list` can print.
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Column {
+    Name,
+    Url,
+    Pin,
+    Sha,
+    Path,
+    /// Source is the manifest the repo was declared in.
+    Source,
+    /// Tags is the repo's tags, comma-separated.
+    Tags,
+}
+
+// Filter narrows the repos listed. Unset fields match everything.
+#[derive(Default, Debug)]
+pub struct Filter {
+    host: Option<String>,
+    org: Option<String>,
+    pattern: Option<Pattern>,
+    globs: Vec<Pattern>,
+    pinned: Option<bool>,
+    present: Option<bool>,
+    tags: Vec<String>,
+    exclude_tags: Vec<String>,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Filter::default()
+    }
+
+    pub fn host(&mut self, host: Option<String>) -> &mut Self {
+        self.host = host;
+        self
+    }
+
+    pub fn org(&mut self, org: Option<String>) -> &mut Self {
+        self.org = org;
+        self
+    }
+
+    /// pattern matches repo names against a glob e.g., "github.com/mccurdyc/*".
+    pub fn pattern(&mut self, pattern: Option<&str>) -> Result<&mut Self> {
+        self.pattern = match pattern {
+            Some(p) => Some(Pattern::new(p).with_context(|| format!("invalid glob: {}", p))?),
+            None => None,
+        };
+        Ok(self)
+    }
+
+    /// globs matches repo names against any of the globs.
+    pub fn globs(&mut self, globs: Vec<Pattern>) -> &mut Self {
+        self.globs = globs;
+        self
+    }
+
+    pub fn pinned(&mut self, pinned: Option<bool>) -> &mut Self {
+        self.pinned = pinned;
+        self
+    }
+
+    /// present filters on whether or not the repo directory exists on disk.
+    pub fn present(&mut self, present: Option<bool>) -> &mut Self {
+        self.present = present;
+        self
+    }
+
+    /// tags matches repos with any of the tags.
+    pub fn tags(&mut self, tags: Vec<String>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+
+    /// exclude_tags matches repos with none of the tags.
+    pub fn exclude_tags(&mut self, tags: Vec<String>) -> &mut Self {
+        self.exclude_tags = tags;
+        self
+    }
+
+    /// selective reports whether the filter selects repos by tag, in which
+    /// case a sync only touches the selected repos.
+    pub fn selective(&self) -> bool {
+        !self.tags.is_empty() || !self.exclude_tags.is_empty()
+    }
+
+    pub fn matches(&self, root: &Path, r: &Repo) -> bool {
+        if let Some(h) = &self.host {
+            if r.get_host() != h {
+                return false;
+            }
+        }
+
+        if let Some(o) = &self.org {
+            if r.get_org() != o {
+                return false;
+            }
+        }
+
+        if let Some(p) = &self.pattern {
+            if !p.matches(r.get_name()) {
+                return false;
+            }
+        }
+
+        if !self.globs.is_empty() && !self.globs.iter().any(|g| g.matches(r.get_name())) {
+            return false;
+        }
+
+        if let Some(pin) = self.pinned {
+            if r.get_pin() != pin {
+                return false;
+            }
+        }
+
+        if let Some(present) = self.present {
+            if root.join(r.get_name()).exists() != present {
+                return false;
+            }
+        }
+
+        if !self.tags.is_empty() && !self.tags.iter().any(|t| r.has_tag(t)) {
+            return false;
+        }
+
+        if self.exclude_tags.iter().any(|t| r.has_tag(t)) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// globs compiles names and globs e.g., "github.com/mccurdyc/*".
+pub fn globs(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).with_context(|| format!("invalid glob: {}", p)))
+        .collect()
+}
+
+/// Listing is the result of the `list` command.
+#[derive(Serialize, Debug)]
+pub struct Listing {
+    repos: Vec<Entry>,
+    #[serde(skip)]
+    columns: Vec<Column>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Entry {
+    name: String,
+    url: String,
+    pin: bool,
+    sha: String,
+    path: PathBuf,
+    present: bool,
+    source: Option<String>,
+    tags: Vec<String>,
+}
+
+impl Listing {
+    pub fn repos(&self) -> &Vec<Entry> {
+        &self.repos
+    }
+}
+
+impl Entry {
+    fn column(&self, c: &Column) -> String {
+        match c {
+            Column::Name => self.name.clone(),
+            Column::Url => self.url.clone(),
+            Column::Pin => self.pin.to_string(),
+            Column::Sha => self.sha.clone(),
+            Column::Path => self.path.display().to_string(),
+            Column::Source => self.source.clone().unwrap_or_default(),
+            Column::Tags => self.tags.join(","),
+        }
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in &self.repos {
+            let row: Vec<String> = self.columns.iter().map(|c| e.column(c)).collect();
+            writeln!(f, "{}", row.join("\t"))?;
+        }
+        Ok(())
+    }
+}
+
+/// list returns the repos in the config that match the filter, sorted by name.
+/// sources is the manifest each repo was declared in.
+pub fn list(
+    root: &Path,
+    repos: &HashMap<String, Repo>,
+    sources: &HashMap<String, String>,
+    filter: &Filter,
+    columns: Vec<Column>,
+) -> Listing {
+    let mut matched: Vec<(&String, &Repo)> = repos
+        .iter()
+        .filter(|(_, r)| filter.matches(root, r))
+        .collect();
+    matched.sort_by(|a, b| a.1.get_name().cmp(b.1.get_name()));
+
+    Listing {
+        repos: matched
+            .into_iter()
+            .map(|(k, r)| {
+                let path = root.join(r.get_name());
+                Entry {
+                    name: r.get_name().to_owned(),
+                    url: r.get_url().to_owned(),
+                    pin: r.get_pin(),
+                    sha: r.get_sha().to_owned(),
+                    present: path.exists(),
+                    path,
+                    source: sources.get(k).cloned(),
+                    tags: r.get_tags().to_vec(),
+                }
+            })
+            .collect(),
+        columns,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::{tempdir, TempDir};
+
+    fn setup() -> (TempDir, HashMap<String, Repo>) {
+        let root = tempdir().expect("Failed to create tempdir");
+
+        let repos = [
+            ("github.com/b/b", true, vec!["oncall"]),
+            ("github.com/a/a", false, vec!["oncall", "payments"]),
+            ("gitlab.com/a/c", false, vec![]),
+        ]
+        .iter()
+        .map(|(n, pin, tags)| {
+            (
+                n.to_string(),
+                Repo::new()
+                    .name(n.to_string())
+                    .expect("name failed")
+                    .pin(*pin)
+                    .tags(tags.iter().map(|t| t.to_string()).collect())
+                    .expect("tags failed")
+                    .to_owned(),
+            )
+        })
+        .collect();
+
+        fs::create_dir_all(root.path().join("github.com/a/a")).expect("failed to create dir");
+
+        (root, repos)
+    }
+
+    fn names(l: &Listing) -> Vec<&str> {
+        l.repos().iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_list_sorted() {
+        let (root, repos) = setup();
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            &Filter::new(),
+            vec![Column::Name],
+        );
+        assert_eq!(
+            names(&got),
+            vec!["github.com/a/a", "github.com/b/b", "gitlab.com/a/c"]
+        );
+        assert_eq!(
+            got.to_string(),
+            "github.com/a/a\ngithub.com/b/b\ngitlab.com/a/c\n"
+        );
+    }
+
+    #[test]
+    fn test_list_filters() {
+        let (root, repos) = setup();
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new().host(Some("github.com".to_string())),
+            vec![],
+        );
+        assert_eq!(names(&got), vec!["github.com/a/a", "github.com/b/b"]);
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new().org(Some("a".to_string())),
+            vec![],
+        );
+        assert_eq!(names(&got), vec!["github.com/a/a", "gitlab.com/a/c"]);
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new().pattern(Some("*/a/*")).expect("bad glob"),
+            vec![],
+        );
+        assert_eq!(names(&got), vec!["github.com/a/a", "gitlab.com/a/c"]);
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new().pinned(Some(true)),
+            vec![],
+        );
+        assert_eq!(names(&got), vec!["github.com/b/b"]);
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new().present(Some(false)),
+            vec![],
+        );
+        assert_eq!(names(&got), vec!["github.com/b/b", "gitlab.com/a/c"]);
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new().tags(vec!["payments".to_string(), "missing".to_string()]),
+            vec![],
+        );
+        assert_eq!(names(&got), vec!["github.com/a/a"]);
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new()
+                .tags(vec!["oncall".to_string()])
+                .exclude_tags(vec!["payments".to_string()]),
+            vec![],
+        );
+        assert_eq!(names(&got), vec!["github.com/b/b"]);
+    }
+
+    #[test]
+    fn test_list_columns() {
+        let (root, repos) = setup();
+
+        let got = list(
+            root.path(),
+            &repos,
+            &HashMap::new(),
+            Filter::new().pinned(Some(true)),
+            vec![
+                Column::Name,
+                Column::Url,
+                Column::Pin,
+                Column::Path,
+                Column::Tags,
+            ],
+        );
+        assert_eq!(
+            got.to_string(),
+            format!(
+                "github.com/b/b\tgit@github.com:b/b.git\ttrue\t{}\toncall\n",
+                root.path().join("github.com/b/b").display()
+            )
+        );
+    }
+}
@@ -1,22 +1,44 @@
-use anyhow::{Context, Error};
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Context, Error};
+use clap::{Args, Parser, Subcommand};
 extern crate log;
-use env_logger;
 use std::env;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
+pub mod adopt;
 pub mod config;
+pub mod daemon;
+pub mod edit;
+pub mod export;
+pub mod foreign;
 pub mod fs;
+pub mod history;
+pub mod import;
+pub mod list;
+pub mod manifest;
+pub mod migrate;
+pub mod output;
+pub mod rename;
 pub mod repo;
+pub mod status;
+pub mod validate;
+pub mod watch;
 
 /// A simple, opinionated, tool, written in Rust, for declaretively managing Git repos on your machine.
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    #[arg(global = true, long, value_name = "FILE")]
+    /// Root directory repos are cloned into. Overridden by GITRS_ROOT.
+    /// Defaults to the config's metadata.root, or $HOME/src.
+    #[arg(global = true, long, value_name = "DIR")]
     root: Option<PathBuf>,
+    /// Config file. Takes precedence over GITRS_CONFIG,
+    /// $XDG_CONFIG_HOME/gitrs/config.yaml and the root's .gitrs.yaml.
     #[arg(global = true, long, value_name = "FILE")]
     config: Option<PathBuf>,
+    /// Output format. See the README for the JSON schema.
+    #[arg(global = true, long, value_enum, default_value_t = output::Format::Text)]
+    output: output::Format,
 
     #[command(subcommand)]
     command: Commands,
@@ -24,23 +46,309 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Add repository to config to be managed by gitrs.
+    /// Add repositories to config to be managed by gitrs, in a single write.
     Add {
-        repo: String,
-        // TODO - implement
+        /// Repos to add. `-` reads them from stdin, one per line.
+        #[arg(required_unless_present = "from_file")]
+        repos: Vec<String>,
+        #[command(flatten)]
+        from_file: FromFile,
         #[arg(short, long)]
         pin: bool,
+        /// Tag the repo, in addition to any tags it already has.
+        #[arg(short, long = "tag", value_name = "TAG", value_delimiter = ',')]
+        tags: Vec<String>,
+        /// Clone the added repos now, without syncing the rest of the root.
+        #[arg(long)]
+        sync: bool,
+    },
+    /// Remove repositories from being managed by gitrs. The next sync removes
+    /// them from the filesystem.
+    Remove {
+        /// Repos to remove. `-` reads them from stdin, one per line.
+        #[arg(required_unless_present = "from_file")]
+        repos: Vec<String>,
+        #[command(flatten)]
+        from_file: FromFile,
+        /// Delete the removed repos now, without syncing the rest of the root.
+        /// Refuses, before changing the config, if any has uncommitted
+        /// changes, stashes or unpushed commits.
+        #[arg(long)]
+        sync: bool,
+        /// Move the removed repos under $GITRS_ROOT/.gitrs-archive instead of
+        /// deleting them.
+        #[arg(long, requires = "sync", conflicts_with = "force")]
+        archive: bool,
+        /// Delete the removed repos even if that loses work.
+        #[arg(long, requires = "sync")]
+        force: bool,
+    },
+    /// Add the git repositories under a directory to the config, deriving
+    /// their names from their origin urls and recording their current branch.
+    Import {
+        /// Directory to search, or with --from, the tool's manifest or root.
+        /// Defaults to the root, or where the tool keeps it by default.
+        path: Option<PathBuf>,
+        /// Pin each repository to its current HEAD.
+        #[arg(short, long, conflicts_with = "from")]
+        pin: bool,
+        /// Import the repositories another tool manages.
+        #[arg(long, value_enum, value_name = "TOOL")]
+        from: Option<foreign::Tool>,
+    },
+    /// Move a repository from outside the root to its canonical path under it
+    /// and add it to the config.
+    Adopt {
+        path: PathBuf,
+        /// Leave a symlink to the new location at the old one.
+        #[arg(long)]
+        link: bool,
+        /// Pin the repository to its current HEAD.
+        #[arg(short, long)]
+        pin: bool,
+    },
+    /// Rename a repository e.g., after it's renamed upstream or moves orgs,
+    /// moving its clone and pointing its origin at the new url instead of
+    /// re-cloning it.
+    Mv { from: String, to: String },
+    /// Add tags to a repository.
+    Tag {
+        repo: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    /// Remove tags from a repository.
+    Untag {
+        repo: String,
+        #[arg(required = true)]
+        tags: Vec<String>,
     },
-    /// Remove repository from the filesystem and from being managed by gitrs.
-    Remove { repo: String },
     /// Sync fetches a repository if it exists, clones it if it doesn't, and
     /// removes it if it exists, but the config no longer has a record for it.
     Sync {
+        /// Only sync repos, and remove directories, whose names match one of
+        /// these names or globs e.g., 'github.com/mccurdyc/*'.
+        #[arg(value_name = "PATTERN")]
+        patterns: Vec<String>,
         // TODO - implement
         /// Force a clean-only sync i.e., don't fetch updates or try to clone missing repos.
         #[arg(short, long)]
         clean_only: bool,
+        /// Refuse to remove more than this many repos at once.
+        #[arg(long, value_name = "N", default_value_t = 25)]
+        max_delete: usize,
+        /// Refuse to remove more than this percentage of repos at once.
+        #[arg(long, value_name = "PERCENT", default_value_t = 50)]
+        max_delete_percent: usize,
+        /// Remove repos even if the mass-deletion checks would refuse.
+        #[arg(long)]
+        allow_mass_delete: bool,
+        /// Selecting repos by tag only clones the selected repos and never
+        /// removes any.
+        #[command(flatten)]
+        tags: Tags,
+    },
+    /// List repositories managed by gitrs, sorted by name.
+    List {
+        /// Only list repos whose name matches a glob e.g., 'github.com/mccurdyc/*'.
+        pattern: Option<String>,
+        /// Only list repos on this host e.g., github.com.
+        #[arg(long)]
+        host: Option<String>,
+        /// Only list repos owned by this org.
+        #[arg(long)]
+        org: Option<String>,
+        /// Only list pinned repos.
+        #[arg(long, conflicts_with = "unpinned")]
+        pinned: bool,
+        /// Only list unpinned repos.
+        #[arg(long)]
+        unpinned: bool,
+        /// Only list repos that exist on disk.
+        #[arg(long, conflicts_with = "missing")]
+        present: bool,
+        /// Only list repos that don't exist on disk yet.
+        #[arg(long)]
+        missing: bool,
+        /// Comma-separated columns to print.
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "name")]
+        columns: Vec<list::Column>,
+        /// Only print absolute paths e.g., for piping into fzf.
+        #[arg(long, conflicts_with = "columns")]
+        paths: bool,
+        #[command(flatten)]
+        tags: Tags,
+    },
+    /// Report the state on disk of every repository in the config and the
+    /// directories the next sync will remove.
+    Status {
+        #[command(flatten)]
+        tags: Tags,
+    },
+    /// Print the repositories in the config in another tool's format e.g., a
+    /// clone script to set up another machine.
+    Export {
+        #[arg(short, long, value_enum, default_value_t = export::Format::Script)]
+        format: export::Format,
+        #[command(flatten)]
+        tags: Tags,
+    },
+    /// Watch the config file and clone or remove repositories as they're added
+    /// to or removed from it.
+    Watch {
+        /// Milliseconds the config file must be quiet before changes are applied.
+        #[arg(long, value_name = "MS", default_value_t = 500)]
+        debounce: u64,
+    },
+    /// List changes to the config file, most recent first. Requires
+    /// metadata.history to be true.
+    History {
+        /// Only list this many changes.
+        #[arg(short = 'n', long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Revert the most recent change to the config file. The revert is itself
+    /// a change, so undoing twice redoes. Requires metadata.history to be true.
+    Undo {},
+    /// Manage the config file itself.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Sync on an interval in the background until SIGTERM or SIGINT.
+    Daemon {
+        /// Minutes between syncs.
+        #[arg(long, value_name = "MINUTES", default_value_t = 60)]
+        interval: u64,
+        /// Maximum random minutes added to each interval.
+        #[arg(long, value_name = "MINUTES", default_value_t = 5)]
+        jitter: u64,
+        /// Local time window in which not to sync e.g., 22:00-07:00.
+        #[arg(long, value_name = "HH:MM-HH:MM")]
+        quiet_hours: Option<daemon::QuietHours>,
+        /// Sync even when running on battery.
+        #[arg(long)]
+        on_battery: bool,
+    },
+}
+
+/// Tags selects repos by tag.
+#[derive(Args)]
+struct Tags {
+    /// Only select repos with any of these tags.
+    #[arg(long = "tag", value_name = "TAG", value_delimiter = ',')]
+    tags: Vec<String>,
+    /// Don't select repos with any of these tags.
+    #[arg(long = "exclude-tag", value_name = "TAG", value_delimiter = ',')]
+    exclude_tags: Vec<String>,
+}
+
+impl Tags {
+    fn filter(&self) -> list::Filter {
+        let mut filter = list::Filter::new();
+        filter
+            .tags(self.tags.clone())
+            .exclude_tags(self.exclude_tags.clone());
+        filter
+    }
+}
+
+#[derive(Args)]
+struct FromFile {
+    /// Also read repos from a file, one per line, or `-` for stdin. Blank
+    /// lines and lines starting with `#` are skipped.
+    #[arg(long, value_name = "FILE")]
+    from_file: Option<PathBuf>,
+}
+
+impl FromFile {
+    // names returns the repos named in args and the file, failing with every
+    // invalid name and where it came from.
+    fn names(&self, args: &[String]) -> anyhow::Result<Vec<String>> {
+        let mut named: Vec<(String, String)> = Vec::new();
+        let mut stdin = false;
+        for a in args {
+            match a.as_str() {
+                "-" if !stdin => {
+                    stdin = true;
+                    named.extend(read_names("-".as_ref())?);
+                }
+                "-" => {}
+                _ => named.push((a.clone(), a.clone())),
+            }
+        }
+        if let Some(p) = &self.from_file {
+            if p.to_str() != Some("-") || !stdin {
+                named.extend(read_names(p)?);
+            }
+        }
+
+        let invalid: Vec<String> = named
+            .iter()
+            .filter_map(|(at, n)| match repo::Repo::new().name(n.clone()) {
+                Ok(_) => None,
+                Err(e) if at == n => Some(format!("{}: {:#}", n, e)),
+                Err(e) => Some(format!("{}: {}: {:#}", at, n, e)),
+            })
+            .collect();
+        if !invalid.is_empty() {
+            return Err(anyhow!("invalid repos:\n{}", invalid.join("\n")));
+        }
+        if named.is_empty() {
+            return Err(anyhow!("no repos given"));
+        }
+        Ok(named.into_iter().map(|(_, n)| n).collect())
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Restore the config file from a backup. The current config file is backed
+    /// up first, so restoring 1 undoes a restore.
+    Restore {
+        /// Backup to restore, where 1 is the most recent.
+        #[arg(default_value_t = 1)]
+        backup: usize,
     },
+    /// Migrate the config file to the current schema version. The original is
+    /// backed up to <config>.<version>.bak. Configs are also migrated whenever
+    /// gitrs reads them.
+    Migrate {
+        /// Only check whether a migration is needed, exiting non-zero if so.
+        #[arg(long)]
+        check: bool,
+    },
+    /// Check the config file, reporting every problem with its line and
+    /// column. Also run before every command that changes the config or the
+    /// filesystem.
+    Validate {},
+    /// Print the config file in effect.
+    Path {},
+}
+
+impl Commands {
+    // mutates reports whether the command changes the config or the
+    // filesystem, and so must only run against a valid config.
+    fn mutates(&self) -> bool {
+        match self {
+            Commands::Add { .. }
+            | Commands::Remove { .. }
+            | Commands::Import { .. }
+            | Commands::Adopt { .. }
+            | Commands::Mv { .. }
+            | Commands::Tag { .. }
+            | Commands::Untag { .. }
+            | Commands::Sync { .. }
+            | Commands::Watch { .. }
+            | Commands::Undo {}
+            | Commands::Daemon { .. } => true,
+            Commands::List { .. }
+            | Commands::Status { .. }
+            | Commands::Export { .. }
+            | Commands::History { .. }
+            | Commands::Config { .. } => false,
+        }
+    }
 }
 
 fn main() -> anyhow::Result<(), Error> {
@@ -54,28 +362,468 @@ fn run(mut c: Cli) -> anyhow::Result<(), Error> {
     if let Ok(root) = env::var("GITRS_ROOT") {
         c.root = Some(PathBuf::from(root));
     }
+    let root = c.root.as_deref().map(fs::expand).transpose()?;
 
-    let r = fs::init(c.root).expect("failed to initialize root");
-    let mut cfg = config::Config::new(r, PathBuf::from(".gitrs.yaml"))?;
+    let started = Instant::now();
+
+    let loc = config::locate(c.config.clone(), fs::root(root.clone()).as_path())?;
+    if let Commands::Config {
+        command: ConfigCommands::Path {},
+    } = &c.command
+    {
+        return output::emit(c.output, "config path", started, &loc);
+    }
+
+    // Restoring must happen before the config is read, because the config
+    // being replaced may not even parse. That's also why the root isn't taken
+    // from it; restoring doesn't need the root.
+    if let Commands::Config {
+        command: ConfigCommands::Restore { backup },
+    } = &c.command
+    {
+        let restored = config::Config::new(fs::root(root.clone()), loc.path())?
+            .restore(*backup)
+            .with_context(|| format!("failed to restore backup: {}", backup))?;
+        return output::emit(c.output, "config restore", started, &restored);
+    }
+
+    // A config file outside of the root says where the root is.
+    let root = match root {
+        Some(r) => Some(r),
+        None if !loc.root_local() && loc.exists() => Some(config::root_of(&loc.path())?),
+        None => None,
+    };
+
+    let r = fs::init(root).context("failed to initialize root")?;
+    let mut cfg = config::Config::new(r, loc.path())?;
+
+    // Checking whether a migration is needed must happen before the config is
+    // read, because reading migrates it.
+    if let Commands::Config {
+        command: ConfigCommands::Migrate { check },
+    } = &c.command
+    {
+        let m = config::migration(cfg.root(), cfg.path(), *check)
+            .context("failed to migrate config")?;
+        output::emit(c.output, "config migrate", started, &m)?;
+
+        if m.needed() {
+            return Err(anyhow!("config needs migrated"));
+        }
+        return Ok(());
+    }
+
+    if let Commands::Config {
+        command: ConfigCommands::Validate {},
+    } = &c.command
+    {
+        let v = validate::validate(cfg.path().as_path(), cfg.root().as_path())?;
+        output::emit(c.output, "config validate", started, &v)?;
+
+        if !v.is_valid() {
+            return Err(anyhow!("invalid config"));
+        }
+        return Ok(());
+    }
+
+    if c.command.mutates() && cfg.path().exists() {
+        let v = validate::validate(cfg.path().as_path(), cfg.root().as_path())?;
+        if !v.is_valid() {
+            return Err(anyhow!("invalid config:\n{}", v.to_string().trim_end()));
+        }
+    }
 
     cfg = match cfg.path().exists() {
-        true => cfg.read(cfg.path()).expect("failed to read config"),
-        false => cfg.create().expect("failed to create config"),
+        true => cfg.read(cfg.path()).context("failed to read config")?,
+        false => cfg.create().context("failed to create config")?,
     };
 
     match &c.command {
-        Commands::Add { repo, pin } => {
-            cfg.add(repo.to_string(), *pin)
-                .with_context(|| format!("failed to add repo: {}", repo))?;
+        Commands::Add {
+            repos,
+            from_file,
+            pin,
+            tags,
+            sync,
+        } => {
+            let names = from_file.names(repos)?;
+            let _lock = match sync {
+                true => Some(
+                    fs::lock(cfg.root().as_path())?
+                        .ok_or_else(|| anyhow!("another sync is running"))?,
+                ),
+                false => None,
+            };
+
+            let changes = cfg
+                .add_all(&names, *pin, tags)
+                .with_context(|| format!("failed to add repos: {}", names.join(", ")))?;
+
+            let report = sync.then(|| {
+                let added: Vec<&repo::Repo> =
+                    names.iter().filter_map(|n| cfg.repos().get(n)).collect();
+                fs::apply(cfg.root().as_path(), &added, &[])
+            });
+            emit_changes(c.output, "add", started, &changes, report)?;
+        }
+        Commands::Remove {
+            repos,
+            from_file,
+            sync,
+            archive,
+            force,
+        } => {
+            let names = from_file.names(repos)?;
+            let root = cfg.root();
+            let _lock = match sync {
+                true => Some(
+                    fs::lock(root.as_path())?.ok_or_else(|| anyhow!("another sync is running"))?,
+                ),
+                false => None,
+            };
+
+            let (changes, report) = remove(&mut cfg, &names, *sync, *archive, *force)?;
+            emit_changes(c.output, "remove", started, &changes, report)?;
+        }
+        Commands::Import { path, pin, from } => {
+            let (found, unmapped) = match from {
+                Some(tool) => {
+                    let p = match path {
+                        Some(p) => p.clone(),
+                        None => tool.default_path()?,
+                    };
+                    foreign::scan(*tool, p.as_path())
+                        .with_context(|| format!("failed to read {:?}", p))?
+                }
+                None => {
+                    let dir = path.clone().unwrap_or_else(|| cfg.root());
+                    import::scan(dir.as_path(), *pin)
+                        .with_context(|| format!("failed to search {:?}", dir))?
+                }
+            };
+
+            let repos: Vec<repo::Repo> = found.iter().map(|f| f.repo().clone()).collect();
+            let added = cfg.import(&repos).context("failed to import repos")?;
+
+            let report = import::Import::new(cfg.root().as_path(), found, unmapped, &added);
+            output::emit(c.output, "import", started, &report)?;
+        }
+        Commands::Adopt { path, link, pin } => {
+            // Hold the sync lock, so that a sync can't remove the repo between
+            // it being moved and it being added to the config.
+            let _lock =
+                fs::lock(cfg.root().as_path())?.ok_or_else(|| anyhow!("a sync is running"))?;
+
+            let (found, to) = adopt::plan(cfg.root().as_path(), path, *pin)
+                .with_context(|| format!("failed to adopt {:?}", path))?;
+            let mut adopted = adopt::adopt(&found, &to, *link)
+                .with_context(|| format!("failed to adopt {:?}", path))?;
+
+            match cfg.import(std::slice::from_ref(found.repo())) {
+                Ok(added) => adopted.added(!added.is_empty()),
+                Err(e) => {
+                    if let Err(u) = adopt::undo(&adopted) {
+                        log::error!("Failed to move {:?} back: {:#}", path, u);
+                    }
+                    return Err(e.context("failed to add repo"));
+                }
+            };
+            output::emit(c.output, "adopt", started, &adopted)?;
+        }
+        Commands::Mv { from, to } => {
+            // Hold the sync lock, so that a sync can't remove the repo between
+            // it being moved and it being renamed in the config.
+            let _lock =
+                fs::lock(cfg.root().as_path())?.ok_or_else(|| anyhow!("a sync is running"))?;
+
+            let mut renamed = rename::plan(cfg.root().as_path(), cfg.repos(), from, to)
+                .with_context(|| format!("failed to rename {} to {}", from, to))?;
+            renamed
+                .mv()
+                .with_context(|| format!("failed to move {}", from))?;
+
+            let r = match cfg.rename(from, to) {
+                Ok(r) => r,
+                Err(e) => {
+                    if let Err(u) = renamed.undo() {
+                        log::error!("Failed to move {} back: {:#}", to, u);
+                    }
+                    return Err(e.context("failed to rename repo"));
+                }
+            };
+            renamed
+                .finish(r.get_url())
+                .with_context(|| format!("renamed {}, but failed to update its clone", to))?;
+            output::emit(c.output, "mv", started, &renamed)?;
+        }
+        Commands::Tag { repo, tags } => {
+            let change = cfg
+                .tag(repo.to_string(), tags)
+                .with_context(|| format!("failed to tag repo: {}", repo))?;
+            output::emit(c.output, "tag", started, &change)?;
+        }
+        Commands::Untag { repo, tags } => {
+            let change = cfg
+                .untag(repo.to_string(), tags)
+                .with_context(|| format!("failed to untag repo: {}", repo))?;
+            output::emit(c.output, "untag", started, &change)?;
+        }
+        Commands::Sync {
+            clean_only,
+            max_delete,
+            max_delete_percent,
+            allow_mass_delete,
+            tags,
+            patterns,
+        } => {
+            let _lock = fs::lock(cfg.root().as_path())?
+                .ok_or_else(|| anyhow!("another sync is running"))?;
+
+            cfg.fetch_manifests().context("failed to fetch manifests")?;
+            let cfg = cfg.read(cfg.path()).context("failed to read config")?;
+
+            let only = list::globs(patterns)?;
+            let mut filter = tags.filter();
+            filter.globs(only.clone());
+
+            let report = match filter.selective() {
+                // A selective sync only clones the selected repos. Nothing is
+                // removed, since everything outside the selection is left as is.
+                true => {
+                    let root = cfg.root();
+                    let mut selected: Vec<&repo::Repo> = cfg
+                        .repos()
+                        .values()
+                        .filter(|r| filter.matches(root.as_path(), r))
+                        .collect();
+                    selected.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                    fs::apply(root.as_path(), &selected, &[])
+                }
+                false => {
+                    let mut guard = fs::Guard::new();
+                    guard
+                        .max_delete(*max_delete)
+                        .max_delete_percent(*max_delete_percent)
+                        .allow_mass_delete(*allow_mass_delete)
+                        .sanity(cfg.sanity_check().err().map(|e| e.to_string()));
+
+                    fs::sync(cfg.root(), cfg.repos(), clean_only, &guard, &only)
+                        .context("failed to sync repos")?
+                }
+            };
+            output::emit(c.output, "sync", started, &report)?;
+
+            if report.failed() > 0 {
+                return Err(anyhow!("failed to sync {} repos", report.failed()));
+            }
+        }
+        Commands::List {
+            pattern,
+            host,
+            org,
+            pinned,
+            unpinned,
+            present,
+            missing,
+            columns,
+            paths,
+            tags,
+        } => {
+            let mut filter = tags.filter();
+            filter
+                .host(host.clone())
+                .org(org.clone())
+                .pinned(flag(*pinned, *unpinned))
+                .present(flag(*present, *missing))
+                .pattern(pattern.as_deref())?;
+
+            let columns = match paths {
+                true => vec![list::Column::Path],
+                false => columns.clone(),
+            };
+
+            let listing = list::list(
+                cfg.root().as_path(),
+                cfg.repos(),
+                cfg.sources(),
+                &filter,
+                columns,
+            );
+            output::emit(c.output, "list", started, &listing)?;
+        }
+        Commands::Status { tags } => {
+            let status = status::status(cfg.root().as_path(), cfg.repos(), &tags.filter())
+                .context("failed to get status")?;
+            output::emit(c.output, "status", started, &status)?;
         }
-        Commands::Remove { repo } => {
-            cfg.remove(repo.to_string())
-                .with_context(|| format!("failed to remove repo: {}", repo))?;
+        Commands::Export { format, tags } => {
+            let filter = tags.filter();
+            let repos = cfg
+                .repos()
+                .iter()
+                .filter(|(_, r)| filter.matches(cfg.root().as_path(), r))
+                .map(|(k, r)| (k.clone(), r.clone()))
+                .collect();
+            let export = export::export(&repos, *format).context("failed to export")?;
+            output::emit(c.output, "export", started, &export)?;
         }
-        Commands::Sync { clean_only } => {
-            fs::sync(cfg.root(), cfg.repos(), clean_only)
-                .with_context(|| format!("failed to sync repos"))?;
+        Commands::Watch { debounce } => {
+            watch::watch(cfg, Duration::from_millis(*debounce), c.output)
+                .context("failed to watch config")?;
         }
+        Commands::History { limit } => {
+            let history = cfg.history(*limit).context("failed to get history")?;
+            output::emit(c.output, "history", started, &history)?;
+        }
+        Commands::Undo {} => {
+            let undo = cfg.undo().context("failed to undo")?;
+            output::emit(c.output, "undo", started, &undo)?;
+        }
+        Commands::Config { command } => match command {
+            ConfigCommands::Restore { .. }
+            | ConfigCommands::Migrate { .. }
+            | ConfigCommands::Validate {}
+            | ConfigCommands::Path {} => {
+                unreachable!("handled before reading the config")
+            }
+        },
+        Commands::Daemon {
+            interval,
+            jitter,
+            quiet_hours,
+            on_battery,
+        } => {
+            let mut opts = daemon::Options::new();
+            opts.interval(Duration::from_secs(interval.saturating_mul(60)))
+                .jitter(Duration::from_secs(jitter.saturating_mul(60)))
+                .quiet_hours(*quiet_hours)
+                .skip_on_battery(!on_battery);
+            daemon::run(cfg, &opts).context("failed to run daemon")?;
+        }
+    }
+    Ok(())
+}
+
+// remove removes names from the config and, with sync, deletes or archives
+// their clones. Only the repos the config actually managed are touched on
+// disk, and without archive or force, any with unsaved work is a refusal
+// that leaves both the config and the root as they were.
+fn remove(
+    cfg: &mut config::Config,
+    names: &[String],
+    sync: bool,
+    archive: bool,
+    force: bool,
+) -> anyhow::Result<(config::Changes, Option<fs::SyncReport>)> {
+    let root = cfg.root();
+    if sync && !archive && !force {
+        let mut unsaved = Vec::new();
+        for n in names.iter().filter(|n| cfg.repos().contains_key(*n)) {
+            let p = root.join(n);
+            if p.join(".git").exists() {
+                for w in status::unsaved(&p).with_context(|| format!("failed to inspect {}", n))? {
+                    unsaved.push(format!("{}: {}", n, w));
+                }
+            }
+        }
+        if !unsaved.is_empty() {
+            return Err(anyhow!(
+                "refusing to delete repos with unsaved work; use --archive or --force:\n{}",
+                unsaved.join("\n")
+            ));
+        }
+    }
+
+    let changes = cfg
+        .remove_all(names)
+        .with_context(|| format!("failed to remove repos: {}", names.join(", ")))?;
+
+    let removed = changes.changed();
+    let report = sync.then(|| match archive {
+        true => fs::archive(root.as_path(), &removed),
+        false => fs::apply(root.as_path(), &[], &removed),
+    });
+    Ok((changes, report))
+}
+
+// emit_changes prints the changes to the config along with, when the command
+// synced them, what it did on disk, failing if any repo couldn't be synced.
+fn emit_changes(
+    format: output::Format,
+    command: &str,
+    started: Instant,
+    changes: &config::Changes,
+    report: Option<fs::SyncReport>,
+) -> anyhow::Result<()> {
+    let failed = report.as_ref().map(|r| r.failed()).unwrap_or_default();
+    match report {
+        Some(r) => output::emit(format, command, started, &fs::Synced::new(changes, r))?,
+        None => output::emit(format, command, started, changes)?,
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("failed to sync {} repos", failed));
     }
     Ok(())
 }
+
+// read_names reads the repos in a file, or stdin when p is `-`, along with
+// the line each came from.
+fn read_names(p: &std::path::Path) -> anyhow::Result<Vec<(String, String)>> {
+    let (text, source) = match p.to_str() {
+        Some("-") => (
+            std::io::read_to_string(std::io::stdin()).context("failed to read stdin")?,
+            "<stdin>".to_string(),
+        ),
+        _ => (
+            std::fs::read_to_string(p).with_context(|| format!("failed to read {:?}", p))?,
+            p.display().to_string(),
+        ),
+    };
+    Ok(text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (format!("{}:{}", source, i + 1), l.trim().to_string()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+        .collect())
+}
+
+// flag collapses a pair of mutually exclusive boolean arguments e.g.,
+// `--pinned`/`--unpinned` into an optional filter value.
+fn flag(yes: bool, no: bool) -> Option<bool> {
+    match (yes, no) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_remove_sync_unmanaged() {
+        let root = tempdir().expect("Failed to create tempdir");
+        let mut cfg = config::Config::new(root.path().to_path_buf(), PathBuf::from("test.yaml"))
+            .expect("new failed")
+            .create()
+            .expect("create failed");
+        cfg.add("github.com/a/a".to_string(), false, &[])
+            .expect("add failed");
+        for n in ["github.com/a/a", "github.com/q/q"] {
+            std::fs::create_dir_all(root.path().join(n)).expect("failed to create dir");
+            std::fs::write(root.path().join(n).join("file"), "keep").expect("failed to write");
+        }
+
+        let names = vec!["github.com/a/a".to_string(), "github.com/q/q".to_string()];
+        let (changes, report) =
+            remove(&mut cfg, &names, true, false, false).expect("remove failed");
+        assert_eq!(changes.changed(), vec!["github.com/a/a"]);
+        assert_eq!(report.expect("expected a report").repos().len(), 1);
+        assert!(!root.path().join("github.com/a/a").exists());
+        // A directory the config didn't manage is left alone.
+        assert!(root.path().join("github.com/q/q/file").exists());
+    }
+}
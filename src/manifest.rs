@@ -0,0 +1,513 @@
+use crate::fs;
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+use log::{error, info};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Layer is the repos a set of manifests declare, keyed by name, with each
+/// repo's fields merged across the manifests that declare it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Layer {
+    entries: BTreeMap<String, Entry>,
+}
+
+/// Entry is a repo's fields, as YAML, and the manifest that first declared it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub fields: Mapping,
+    pub source: String,
+}
+
+/// Include is an entry in a manifest's `include` list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Include {
+    /// File is a local manifest.
+    File(PathBuf),
+    /// Git is a manifest in a git repo.
+    Git(Remote),
+}
+
+/// Remote is a manifest file in a git repo, read at a ref so that everyone
+/// including it sees the same repos e.g.,
+/// `{git: git@github.com:team/manifests.git, path: gitrs.yaml, ref: v3}`.
+///
+/// The repo is cached, bare, under gitrs' cache directory, usually
+/// `fs::cache_dir()`. It's cloned the first time it's needed and fetched
+/// before every sync.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Remote {
+    git: String,
+    path: String,
+    /// reference is a branch, tag or commit sha.
+    #[serde(rename = "ref")]
+    reference: String,
+}
+
+impl Remote {
+    /// fetch clones or fetches the repo into the cache directory cache.
+    pub fn fetch(&self, cache: &Path) -> Result<()> {
+        info!("Fetching manifest repo {}", self.git);
+        fs::fetch_bare(&self.git, self.cache(cache).as_path())
+            .with_context(|| format!("failed to fetch manifest repo {}", self.git))?;
+        Ok(())
+    }
+
+    /// read returns the manifest's text at the ref, as of the last fetch. The
+    /// repo is fetched first if it isn't cached yet.
+    pub fn read(&self, cache: &Path) -> Result<String> {
+        let dir = self.cache(cache);
+        if !dir.exists() {
+            self.fetch(cache)?;
+        }
+
+        let repo = git2::Repository::open_bare(&dir)?;
+        let commit = resolve(&repo, &self.reference).with_context(|| {
+            format!("failed to resolve ref {:?} in {}", self.reference, self.git)
+        })?;
+        let entry = commit
+            .tree()?
+            .get_path(Path::new(&self.path))
+            .with_context(|| {
+                format!(
+                    "{} doesn't have {:?} at {}",
+                    self.git, self.path, self.reference
+                )
+            })?;
+        let blob = entry.to_object(&repo)?.peel_to_blob()?;
+
+        Ok(std::str::from_utf8(blob.content())
+            .with_context(|| format!("{} isn't UTF-8", self))?
+            .to_owned())
+    }
+
+    // cache is the directory under dir the repo is cached in, named after its
+    // url.
+    fn cache(&self, dir: &Path) -> PathBuf {
+        let name: String = self
+            .git
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect();
+        dir.join("manifests").join(name)
+    }
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}//{}@{}", self.git, self.path, self.reference)
+    }
+}
+
+// resolve finds the commit a ref names, preferring the remote's branches and
+// tags over anything local to the cache.
+fn resolve<'a>(repo: &'a git2::Repository, reference: &str) -> Result<git2::Commit<'a>> {
+    for r in [
+        format!("refs/remotes/origin/{}", reference),
+        format!("refs/tags/{}", reference),
+    ] {
+        if let Ok(r) = repo.find_reference(&r) {
+            return Ok(r.peel_to_commit()?);
+        }
+    }
+    Ok(repo.revparse_single(reference)?.peel_to_commit()?)
+}
+
+/// fetch fetches every git manifest the manifest v includes. Failures are
+/// logged rather than returned, so that syncs continue with the cached
+/// manifest while offline.
+pub fn fetch(v: &Value, path: &Path) -> Result<()> {
+    for i in includes(v, path, |k| std::env::var(k).ok())? {
+        if let Include::Git(r) = i {
+            if let Err(e) = fs::cache_dir().and_then(|c| r.fetch(&c)) {
+                error!("Using cached manifest {}: {:#}", r, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Layer {
+    /// declared returns the repos declared by the manifest v at path, not
+    /// including those it includes.
+    pub fn declared(v: &Value, source: &str) -> Result<Self> {
+        let mut entries = BTreeMap::new();
+
+        match v.get("repos") {
+            None | Some(Value::Null) => {}
+            Some(Value::Mapping(repos)) => {
+                for (k, r) in repos {
+                    let name = k
+                        .as_str()
+                        .ok_or_else(|| anyhow!("repo key {:?} isn't a string", k))?;
+                    let mut fields = fields(r, name)?;
+                    fields
+                        .entry(Value::String("name".to_owned()))
+                        .or_insert_with(|| Value::String(name.to_owned()));
+                    entries.insert(name.to_owned(), entry(fields, source));
+                }
+            }
+            Some(Value::Sequence(repos)) => {
+                for (i, r) in repos.iter().enumerate() {
+                    let name = r
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .ok_or_else(|| anyhow!("repos[{}] doesn't have a name", i))?;
+                    let fields = fields(r, name)?;
+                    if entries
+                        .insert(name.to_owned(), entry(fields, source))
+                        .is_some()
+                    {
+                        return Err(anyhow!("duplicate repo {:?}", name));
+                    }
+                }
+            }
+            Some(_) => return Err(anyhow!("repos isn't a mapping or a list")),
+        }
+
+        Ok(Layer { entries })
+    }
+
+    /// overlay merges other over self. Fields set in other override those in
+    /// self; a repo keeps the source that first declared it.
+    pub fn overlay(&mut self, other: Layer) {
+        for (name, e) in other.entries {
+            match self.entries.get_mut(&name) {
+                Some(existing) => {
+                    for (k, v) in e.fields {
+                        existing.fields.insert(k, v);
+                    }
+                }
+                None => {
+                    self.entries.insert(name, e);
+                }
+            }
+        }
+    }
+
+    /// exclude drops the repos whose names match any of patterns.
+    pub fn exclude(&mut self, patterns: &[Pattern]) {
+        self.entries
+            .retain(|name, _| !patterns.iter().any(|p| p.matches(name)));
+    }
+
+    /// insert declares the repo name with fields, replacing any previous
+    /// declaration.
+    pub fn insert(&mut self, name: &str, fields: Mapping, source: &str) {
+        self.entries.insert(name.to_owned(), entry(fields, source));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.remove(name);
+    }
+
+    pub fn entries(&self) -> &BTreeMap<String, Entry> {
+        &self.entries
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+}
+
+/// inherited returns the repos that the manifest v at path inherits from the
+/// manifests it includes, after its excludes are applied.
+///
+/// Later includes take precedence over earlier ones, and a manifest's own
+/// repos take precedence over those it includes. Include paths may use `~`
+/// and environment variables and are relative to the including manifest.
+pub fn inherited(v: &Value, path: &Path) -> Result<Layer> {
+    inherited_with(v, path, |k| std::env::var(k).ok())
+}
+
+/// inherited_with is inherited with var in place of the environment when
+/// expanding include paths and locating the cache.
+pub fn inherited_with(
+    v: &Value,
+    path: &Path,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Layer> {
+    walk(v, path, &var, &mut vec![])
+}
+
+fn walk(
+    v: &Value,
+    path: &Path,
+    var: &dyn Fn(&str) -> Option<String>,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Layer> {
+    stack.push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+
+    let mut layer = Layer::default();
+    for i in includes(v, path, var)? {
+        let l = match i {
+            Include::File(p) => {
+                let canonical = p
+                    .canonicalize()
+                    .with_context(|| format!("failed to include {:?}", p))?;
+                if stack.contains(&canonical) {
+                    return Err(anyhow!("{:?} includes itself", p));
+                }
+
+                let text = std::fs::read_to_string(&p)
+                    .with_context(|| format!("failed to include {:?}", p))?;
+                let included: Value = serde_yaml::from_str(&text)
+                    .with_context(|| format!("failed to parse included {:?}", p))?;
+
+                let mut l = walk(&included, &canonical, var, stack)?;
+                l.overlay(
+                    Layer::declared(&included, &canonical.display().to_string())
+                        .with_context(|| format!("failed to parse included {:?}", p))?,
+                );
+                l
+            }
+            Include::Git(r) => {
+                let included: Value = serde_yaml::from_str(&r.read(&fs::cache_dir_with(var)?)?)
+                    .with_context(|| format!("failed to parse included {}", r))?;
+                if !includes(&included, path, var)?.is_empty() {
+                    return Err(anyhow!("{} can't include other manifests", r));
+                }
+
+                let mut l = Layer::declared(&included, &r.to_string())
+                    .with_context(|| format!("failed to parse included {}", r))?;
+                l.exclude(&excludes(&included)?);
+                l
+            }
+        };
+        layer.overlay(l);
+    }
+    layer.exclude(&excludes(v)?);
+
+    stack.pop();
+    Ok(layer)
+}
+
+/// includes returns the manifest's `include` list, with local paths expanded
+/// using var and resolved relative to the manifest at path.
+pub fn includes(
+    v: &Value,
+    path: &Path,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<Include>> {
+    let items = match v.get("include") {
+        None | Some(Value::Null) => return Ok(vec![]),
+        Some(Value::Sequence(s)) => s,
+        Some(_) => return Err(anyhow!("include should be a list")),
+    };
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    items
+        .iter()
+        .map(|i| match i {
+            Value::String(s) => Ok(Include::File(
+                dir.join(fs::expand_with(Path::new(s), &var)?),
+            )),
+            Value::Mapping(_) => Ok(Include::Git(
+                serde_yaml::from_value(i.clone()).context("invalid git include")?,
+            )),
+            _ => Err(anyhow!(
+                "include should be a list of paths or {{git, path, ref}} mappings"
+            )),
+        })
+        .collect()
+}
+
+/// excludes returns the manifest's `exclude` list as globs.
+pub fn excludes(v: &Value) -> Result<Vec<Pattern>> {
+    strings(v, "exclude")?
+        .into_iter()
+        .map(|s| Pattern::new(s).with_context(|| format!("invalid exclude glob: {}", s)))
+        .collect()
+}
+
+fn strings<'a>(v: &'a Value, key: &str) -> Result<Vec<&'a str>> {
+    match v.get(key) {
+        None | Some(Value::Null) => Ok(vec![]),
+        Some(Value::Sequence(s)) => s
+            .iter()
+            .map(|i| {
+                i.as_str()
+                    .ok_or_else(|| anyhow!("{} should be a list of strings", key))
+            })
+            .collect(),
+        Some(_) => Err(anyhow!("{} should be a list of strings", key)),
+    }
+}
+
+fn fields(r: &Value, name: &str) -> Result<Mapping> {
+    match r {
+        Value::Mapping(m) => Ok(m.clone()),
+        _ => Err(anyhow!("repo {:?} isn't a mapping", name)),
+    }
+}
+
+fn entry(fields: Mapping, source: &str) -> Entry {
+    Entry {
+        fields,
+        source: source.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn parse(s: &str) -> Value {
+        serde_yaml::from_str(s).expect("failed to parse")
+    }
+
+    #[test]
+    fn test_inherited() {
+        let root = tempdir().expect("Failed to create tempdir");
+        let p = root.path();
+
+        std::fs::write(
+            p.join("base.yaml"),
+            "repos:\n- name: github.com/a/a\n- name: github.com/b/b\n  pin: true\n  sha: abc1234\n",
+        )
+        .unwrap();
+        std::fs::write(
+            p.join("team.yaml"),
+            "include: [base.yaml]\nexclude: ['github.com/a/*']\nrepos:\n  github.com/b/b: {sha: def5678}\n  github.com/c/c: {}\n",
+        )
+        .unwrap();
+
+        let v = parse("include: [team.yaml]\nrepos: {}\n");
+        let got = inherited(&v, p.join("gitrs.yaml").as_path()).expect("inherited failed");
+
+        assert_eq!(
+            got.entries().keys().collect::<Vec<_>>(),
+            vec!["github.com/b/b", "github.com/c/c"]
+        );
+
+        let b = &got.entries()["github.com/b/b"];
+        assert_eq!(b.source, p.join("base.yaml").display().to_string());
+        assert_eq!(b.fields.get("pin"), Some(&Value::Bool(true)));
+        assert_eq!(
+            b.fields.get("sha"),
+            Some(&Value::String("def5678".to_string()))
+        );
+        assert_eq!(
+            got.entries()["github.com/c/c"].source,
+            p.join("team.yaml").display().to_string()
+        );
+
+        // Include paths are expanded with the environment given.
+        let v = parse("include: [$TEAM/team.yaml]\nrepos: {}\n");
+        let var = |k: &str| (k == "TEAM").then(|| p.display().to_string());
+        let with =
+            inherited_with(&v, Path::new("/elsewhere/gitrs.yaml"), var).expect("inherited failed");
+        assert_eq!(with, got);
+    }
+
+    #[test]
+    fn test_inherited_cycle() {
+        let root = tempdir().expect("Failed to create tempdir");
+        let p = root.path();
+
+        std::fs::write(p.join("a.yaml"), "include: [b.yaml]\n").unwrap();
+        std::fs::write(p.join("b.yaml"), "include: [a.yaml]\n").unwrap();
+
+        let v = parse("include: [a.yaml]\n");
+        let got = inherited(&v, p.join("a.yaml").as_path());
+        assert!(got.unwrap_err().to_string().contains("includes itself"));
+
+        let v = parse("include: [missing.yaml]\n");
+        assert!(inherited(&v, p.join("gitrs.yaml").as_path()).is_err());
+    }
+
+    // commit commits text as gitrs.yaml on HEAD.
+    fn commit(repo: &git2::Repository, text: &str) -> git2::Oid {
+        let dir = repo.workdir().expect("repo is bare");
+        std::fs::write(dir.join("gitrs.yaml"), text).expect("failed to write manifest");
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("gitrs.yaml")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let sig = git2::Signature::now("test", "test@example.com").expect("signature failed");
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, "manifest", &tree, &parents)
+            .expect("failed to commit")
+    }
+
+    #[test]
+    fn test_remote() {
+        let root = tempdir().expect("Failed to create tempdir");
+        let p = root.path();
+        let cache = p.join("cache");
+
+        let upstream = git2::Repository::init(p.join("upstream")).expect("failed to init repo");
+        let first = commit(&upstream, "repos:\n- name: github.com/a/a\n");
+        upstream
+            .tag_lightweight("v1", &upstream.find_object(first, None).unwrap(), false)
+            .unwrap();
+        let branch = upstream.head().unwrap().shorthand().unwrap().to_string();
+
+        let remote = |reference: &str| Remote {
+            git: p.join("upstream").display().to_string(),
+            path: "gitrs.yaml".to_string(),
+            reference: reference.to_string(),
+        };
+
+        assert_eq!(
+            remote(&branch).read(&cache).expect("read failed"),
+            "repos:\n- name: github.com/a/a\n"
+        );
+
+        // Reads use the cache until it's fetched.
+        commit(&upstream, "repos:\n- name: github.com/b/b\n");
+        assert_eq!(
+            remote(&branch).read(&cache).expect("read failed"),
+            "repos:\n- name: github.com/a/a\n"
+        );
+
+        remote(&branch).fetch(&cache).expect("fetch failed");
+        assert_eq!(
+            remote(&branch).read(&cache).expect("read failed"),
+            "repos:\n- name: github.com/b/b\n"
+        );
+        assert_eq!(
+            remote("v1").read(&cache).expect("read failed"),
+            "repos:\n- name: github.com/a/a\n"
+        );
+        assert_eq!(
+            remote(&first.to_string())
+                .read(&cache)
+                .expect("read failed"),
+            "repos:\n- name: github.com/a/a\n"
+        );
+        assert!(remote("v2").read(&cache).is_err());
+
+        let v: Value = serde_yaml::from_str(&format!(
+            "include:\n- {{git: {}, path: gitrs.yaml, ref: v1}}\n",
+            p.join("upstream").display()
+        ))
+        .unwrap();
+        let var = |k: &str| (k == "XDG_CACHE_HOME").then(|| cache.display().to_string());
+        let got =
+            inherited_with(&v, p.join("gitrs.yaml").as_path(), var).expect("inherited failed");
+        assert_eq!(
+            got.entries()["github.com/a/a"].source,
+            format!("{}//gitrs.yaml@v1", p.join("upstream").display())
+        );
+    }
+
+    #[test]
+    fn test_declared_duplicate() {
+        let v = parse("repos:\n- name: github.com/a/a\n- name: github.com/a/a\n");
+        assert!(Layer::declared(&v, "/x.yaml").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Result};
+use serde_yaml::Value;
+
+/// VERSIONS lists every config schema version gitrs has used, oldest first.
+/// The last one is the version gitrs writes.
+pub const VERSIONS: &[&str] = &["v1beta"];
+
+/// A Migration upgrades a config, as YAML, from one schema version to the
+/// next. It doesn't need to update `metadata.version`.
+pub type Migration = fn(&mut Value) -> Result<()>;
+
+/// MIGRATIONS[i] migrates VERSIONS[i] to VERSIONS[i + 1].
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// current returns the schema version gitrs writes.
+pub fn current() -> &'static str {
+    VERSIONS[VERSIONS.len() - 1]
+}
+
+/// version returns the config's `metadata.version`.
+pub fn version(v: &Value) -> Result<&str> {
+    v.get("metadata")
+        .and_then(|m| m.get("version"))
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("config doesn't have a metadata.version"))
+}
+
+/// needed reports whether the config needs migrated, erroring if it can't be
+/// e.g., it was written by a newer gitrs.
+pub fn needed(v: &Value) -> Result<bool> {
+    needed_with(v, VERSIONS)
+}
+
+fn needed_with(v: &Value, versions: &[&str]) -> Result<bool> {
+    let from = version(v)?;
+    match versions.iter().position(|v| *v == from) {
+        Some(i) => Ok(i < versions.len() - 1),
+        None => Err(anyhow!(
+            "config version {:?} isn't supported by this gitrs, which supports up to {:?}; it was likely written by a newer gitrs, so upgrade gitrs",
+            from,
+            versions[versions.len() - 1]
+        )),
+    }
+}
+
+/// migrate runs the chain of migrations from the config's version to the
+/// current one. Returns the version it migrated from, or None if the config
+/// was already current.
+pub fn migrate(v: &mut Value) -> Result<Option<String>> {
+    migrate_with(v, VERSIONS, MIGRATIONS)
+}
+
+fn migrate_with(
+    v: &mut Value,
+    versions: &[&str],
+    migrations: &[Migration],
+) -> Result<Option<String>> {
+    if !needed_with(v, versions)? {
+        return Ok(None);
+    }
+
+    let from = version(v)?.to_owned();
+    let start = versions.iter().position(|v| *v == from).unwrap_or_default();
+
+    for (i, m) in migrations.iter().enumerate().skip(start) {
+        m(v).map_err(|e| {
+            anyhow!(
+                "failed to migrate config from {} to {}: {:#}",
+                versions[i],
+                versions[i + 1],
+                e
+            )
+        })?;
+
+        v["metadata"]["version"] = Value::String(versions[i + 1].to_owned());
+    }
+
+    Ok(Some(from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERSIONS: &[&str] = &["v0", "v1", "v2"];
+
+    // v0 called repos "projects".
+    fn v0_to_v1(v: &mut Value) -> Result<()> {
+        let m = v.as_mapping_mut().ok_or_else(|| anyhow!("not a mapping"))?;
+        if let Some(p) = m.remove("projects") {
+            m.insert(Value::String("repos".to_owned()), p);
+        }
+        Ok(())
+    }
+
+    // v1 didn't have a root.
+    fn v1_to_v2(v: &mut Value) -> Result<()> {
+        v["metadata"]["root"] = Value::String("/src".to_owned());
+        Ok(())
+    }
+
+    const MIGRATIONS: &[Migration] = &[v0_to_v1, v1_to_v2];
+
+    fn parse(s: &str) -> Value {
+        serde_yaml::from_str(s).expect("failed to parse")
+    }
+
+    #[test]
+    fn test_migrate_chain() {
+        let mut v = parse("metadata: {version: v0}\nprojects: {}\n");
+
+        let got = migrate_with(&mut v, VERSIONS, MIGRATIONS).expect("migrate failed");
+        assert_eq!(got, Some("v0".to_string()));
+        assert_eq!(v, parse("metadata: {version: v2, root: /src}\nrepos: {}\n"));
+
+        let mut v = parse("metadata: {version: v1}\nrepos: {}\n");
+        migrate_with(&mut v, VERSIONS, MIGRATIONS).expect("migrate failed");
+        assert_eq!(v, parse("metadata: {version: v2, root: /src}\nrepos: {}\n"));
+    }
+
+    #[test]
+    fn test_migrate_current() {
+        let mut v = parse("metadata: {version: v2}\nrepos: {}\n");
+        let want = v.clone();
+
+        let got = migrate_with(&mut v, VERSIONS, MIGRATIONS).expect("migrate failed");
+        assert_eq!(got, None);
+        assert_eq!(v, want);
+    }
+
+    #[test]
+    fn test_migrate_newer() {
+        let mut v = parse("metadata: {version: v3}\n");
+        let got = migrate_with(&mut v, VERSIONS, MIGRATIONS);
+        assert!(got.unwrap_err().to_string().contains("upgrade gitrs"));
+
+        let mut v = parse("metadata: {}\n");
+        assert!(migrate_with(&mut v, VERSIONS, MIGRATIONS).is_err());
+    }
+
+    #[test]
+    fn test_versions() {
+        assert_eq!(MIGRATIONS.len(), VERSIONS.len() - 1);
+        assert_eq!(super::MIGRATIONS.len(), super::VERSIONS.len() - 1);
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt::Display;
+use std::time::Instant;
+
+/// SCHEMA_VERSION is the version of the JSON output schema. It is bumped any
+/// time a field is removed or changes meaning. Adding fields is not a breaking
+/// change.
+pub const SCHEMA_VERSION: &str = "v2";
+
+/// Format is the output format selected via the global `--output` argument.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Human readable output.
+    #[default]
+    Text,
+    /// Machine readable output following the documented, versioned schema.
+    Json,
+}
+
+/// Report is the envelope every command's JSON output is wrapped in.
+#[derive(Serialize, Debug)]
+pub struct Report<'a, T: Serialize> {
+    schema_version: &'static str,
+    command: &'a str,
+    duration_ms: u128,
+    result: &'a T,
+}
+
+impl<'a, T: Serialize> Report<'a, T> {
+    pub fn new(command: &'a str, started: Instant, result: &'a T) -> Self {
+        Report {
+            schema_version: SCHEMA_VERSION,
+            command,
+            duration_ms: started.elapsed().as_millis(),
+            result,
+        }
+    }
+}
+
+/// emit prints the result of a command to stdout in the requested format.
+pub fn emit<T: Serialize + Display>(
+    format: Format,
+    command: &str,
+    started: Instant,
+    result: &T,
+) -> Result<()> {
+    match format {
+        Format::Text => {
+            let s = result.to_string();
+            if !s.is_empty() {
+                println!("{}", s.trim_end());
+            }
+        }
+        Format::Json => {
+            let r = Report::new(command, started, result);
+            println!("{}", serde_json::to_string(&r)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_report_envelope() {
+        let result = vec!["a", "b"];
+        let r = Report::new("list", Instant::now(), &result);
+
+        let got: Value = serde_json::to_value(&r).expect("failed to serialize report");
+        assert_eq!(got["schema_version"], SCHEMA_VERSION);
+        assert_eq!(got["command"], "list");
+        assert_eq!(got["result"], serde_json::json!(["a", "b"]));
+        assert!(got["duration_ms"].is_u64());
+    }
+}
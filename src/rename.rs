@@ -0,0 +1,241 @@
+use crate::adopt;
+use crate::fs::{contained, prune};
+use crate::repo::Repo;
+use anyhow::{anyhow, Context, Result};
+use git2::Repository;
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Rename is the result of the `mv` command.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Rename {
+    from: String,
+    to: String,
+    /// moved is false when the repo wasn't cloned, so only the config changed.
+    moved: bool,
+    /// origin is the url the origin remote was changed to, if it changed.
+    origin: Option<String>,
+    /// worktrees are the linked worktrees that were repointed at the moved repo.
+    worktrees: Vec<PathBuf>,
+    /// pruned are the directories left empty by the move, which were removed.
+    pruned: Vec<PathBuf>,
+    /// link is true when the repo's path was a symlink, which was moved as is.
+    #[serde(skip)]
+    link: bool,
+    #[serde(skip)]
+    root: PathBuf,
+    #[serde(skip)]
+    src: PathBuf,
+    #[serde(skip)]
+    dst: PathBuf,
+}
+
+impl fmt::Display for Rename {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "renamed {} to {}", self.from, self.to)?;
+        if self.moved {
+            writeln!(f, "moved {} to {}", self.src.display(), self.dst.display())?;
+        }
+        if let Some(o) = &self.origin {
+            writeln!(f, "set origin to {}", o)?;
+        }
+        for w in &self.worktrees {
+            writeln!(f, "repointed worktree {}", w.display())?;
+        }
+        for p in &self.pruned {
+            writeln!(f, "removed empty directory {}", p.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// plan checks that the repo from can be renamed to to, both in the config
+/// and under root, before anything is changed.
+pub fn plan(root: &Path, repos: &HashMap<String, Repo>, from: &str, to: &str) -> Result<Rename> {
+    if !repos.contains_key(from) {
+        return Err(anyhow!("{} is not managed by gitrs", from));
+    }
+    if repos.contains_key(to) {
+        return Err(anyhow!("{} is already managed by gitrs", to));
+    }
+    Repo::new().name(to.to_owned())?;
+
+    // prune compares against the root, so it has to be in the same form as
+    // the paths under it.
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("failed to resolve root {:?}", root))?;
+    let src = contained(&root, &root.join(from))?;
+    let dst = contained(&root, &root.join(to))?;
+    if dst.exists() || dst.is_symlink() {
+        return Err(anyhow!("{:?} already exists", dst));
+    }
+
+    Ok(Rename {
+        from: from.to_owned(),
+        to: to.to_owned(),
+        moved: false,
+        origin: None,
+        worktrees: Vec::new(),
+        pruned: Vec::new(),
+        link: false,
+        root,
+        src,
+        dst,
+    })
+}
+
+impl Rename {
+    /// mv moves the repo's directory, if it's cloned, and repoints its linked
+    /// worktrees.
+    pub fn mv(&mut self) -> Result<()> {
+        if !self.src.exists() && !self.src.is_symlink() {
+            return Ok(());
+        }
+
+        info!("Moving {:?} to {:?}", self.src, self.dst);
+        fs::create_dir_all(self.dst.parent().unwrap_or(&self.dst))?;
+
+        // A link is moved, rather than what it points to, which may be another
+        // repo entirely.
+        if self.src.is_symlink() {
+            fs::rename(&self.src, &self.dst)
+                .with_context(|| format!("failed to move {:?}", self.src))?;
+            self.moved = true;
+            self.link = true;
+            return Ok(());
+        }
+
+        adopt::move_dir(&self.src, &self.dst)?;
+        self.moved = true;
+
+        self.worktrees =
+            adopt::repoint(&self.src, &self.dst).context("failed to repoint worktrees")?;
+        Ok(())
+    }
+
+    /// undo moves the repo back, e.g., when the config can't be written.
+    pub fn undo(&mut self) -> Result<()> {
+        if !self.moved {
+            return Ok(());
+        }
+        match self.link {
+            true => fs::rename(&self.dst, &self.src)?,
+            false => {
+                adopt::move_dir(&self.dst, &self.src)?;
+                adopt::repoint(&self.dst, &self.src)?;
+            }
+        }
+        self.moved = false;
+        if let Some(p) = self.dst.parent() {
+            prune(&self.root, p);
+        }
+        Ok(())
+    }
+
+    /// finish points the moved repo's origin remote at url and removes the
+    /// directories the move left empty.
+    pub fn finish(&mut self, url: &str) -> Result<()> {
+        if !self.moved {
+            return Ok(());
+        }
+
+        // A link's target isn't the repo's own clone, so it's left alone.
+        if !self.link {
+            let git = Repository::open(&self.dst)?;
+            let current = git
+                .find_remote("origin")
+                .ok()
+                .map(|o| o.url().map(|u| u.to_owned()));
+            if current.is_some_and(|u| u.as_deref() != Some(url)) {
+                git.remote_set_url("origin", url)?;
+                self.origin = Some(url.to_owned());
+            }
+        }
+
+        if let Some(p) = self.src.parent() {
+            self.pruned = prune(&self.root, p);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn repos(names: &[&str]) -> HashMap<String, Repo> {
+        names
+            .iter()
+            .map(|n| {
+                let r = Repo::new().name(n.to_string()).unwrap().to_owned();
+                (n.to_string(), r)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = tempdir().expect("Failed to create tempdir");
+        let real = dir.path().canonicalize().unwrap().join("real");
+        fs::create_dir_all(&real).unwrap();
+        // Through a link, so that the root given isn't canonical.
+        let root = dir.path().join("root");
+        std::os::unix::fs::symlink(&real, &root).unwrap();
+        let git = Repository::init(root.join("github.com/old/a")).expect("failed to init");
+        git.remote("origin", "git@github.com:old/a.git")
+            .expect("failed to add remote");
+        let all = repos(&["github.com/old/a", "github.com/new/b"]);
+
+        assert!(plan(&root, &all, "github.com/x/x", "github.com/new/a").is_err());
+        assert!(plan(&root, &all, "github.com/old/a", "github.com/new/b").is_err());
+        assert!(plan(&root, &all, "github.com/old/a", "github.com/new").is_err());
+
+        let mut got =
+            plan(&root, &all, "github.com/old/a", "github.com/new/a").expect("plan failed");
+        got.mv().expect("mv failed");
+        assert!(root.join("github.com/new/a/.git").is_dir());
+
+        // Undoing moves it back and removes the directories it created.
+        got.undo().expect("undo failed");
+        assert!(root.join("github.com/old/a/.git").is_dir());
+        assert!(!root.join("github.com/new").exists());
+
+        got.mv().expect("mv failed");
+        got.finish("git@github.com:new/a.git")
+            .expect("finish failed");
+        assert_eq!(got.origin.as_deref(), Some("git@github.com:new/a.git"));
+        assert_eq!(got.pruned, vec![real.join("github.com/old")]);
+        let git = Repository::open(root.join("github.com/new/a")).unwrap();
+        assert_eq!(
+            git.find_remote("origin").unwrap().url(),
+            Some("git@github.com:new/a.git")
+        );
+
+        // A link is moved as is, leaving the repo it points to alone.
+        fs::create_dir_all(real.join("github.com/l")).unwrap();
+        std::os::unix::fs::symlink(real.join("github.com/new/a"), real.join("github.com/l/l"))
+            .unwrap();
+        let all = repos(&["github.com/l/l"]);
+        let mut got = plan(&root, &all, "github.com/l/l", "github.com/m/m").expect("plan failed");
+        got.mv().expect("mv failed");
+        got.finish("git@github.com:m/m.git").expect("finish failed");
+        assert!(real.join("github.com/m/m").is_symlink());
+        assert!(real.join("github.com/new/a/.git").is_dir());
+        assert_eq!(got.origin, None);
+        assert_eq!(got.pruned, vec![real.join("github.com/l")]);
+
+        // A repo that isn't cloned only changes in the config.
+        let all = repos(&["github.com/c/c"]);
+        let mut got = plan(&root, &all, "github.com/c/c", "github.com/d/d").expect("plan failed");
+        got.mv().expect("mv failed");
+        got.finish("git@github.com:d/d.git").expect("finish failed");
+        assert!(!got.moved);
+        assert_eq!(got.origin, None);
+    }
+}
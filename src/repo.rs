@@ -1,13 +1,22 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use log::debug;
 use serde::{Deserialize, Serialize};
 
+// Every field may be omitted from the config; see fill.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
 pub struct Repo {
     name: String,
     url: String,
     pin: bool,
     sha: String,
+    /// branch is checked out when the repo is cloned, instead of the remote's
+    /// default branch.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    branch: String,
+    /// tags group repos for selective operations e.g., `team-payments`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
 }
 
 // Modeling after OpenOptions. This is so that Repo struct fields can change, but
@@ -19,6 +28,8 @@ impl Repo {
             url: "".to_owned(),
             pin: false,
             sha: "".to_owned(),
+            branch: "".to_owned(),
+            tags: Vec::new(),
         }
     }
 
@@ -29,10 +40,36 @@ impl Repo {
         Ok(self)
     }
 
+    /// rename changes the repo's name. A url other than the one derived from
+    /// the old name keeps its form, e.g., its scheme, user and port, and has
+    /// only its <org>/<repo> path, and its host if it's the old name's,
+    /// rewritten.
+    pub fn rename(&mut self, name: String) -> Result<&mut Self> {
+        let from = self.name.clone();
+        let url = self.url.clone();
+        let derived = Repo::new().name(from.clone()).map(|r| r.url.clone());
+        self.name(name)?;
+
+        if !url.is_empty() && derived.ok().as_deref() != Some(url.as_str()) {
+            self.url = rewrite_url(&url, &from, &self.name);
+        }
+        Ok(self)
+    }
+
     pub fn get_name(&self) -> &str {
         self.name.as_str()
     }
 
+    /// get_host returns the <host> segment of the name.
+    pub fn get_host(&self) -> &str {
+        self.name.split('/').next().unwrap_or_default()
+    }
+
+    /// get_org returns the <org> segment of the name.
+    pub fn get_org(&self) -> &str {
+        self.name.split('/').nth(1).unwrap_or_default()
+    }
+
     // url changes the name of the format "github.com/<org>/<name>" to Git SSH
     // protocol format.
     //
@@ -48,10 +85,40 @@ impl Repo {
             ));
         }
 
+        // The name is also the repo's path relative to the root, so each
+        // segment must be a plain directory name that can't escape the root.
+        for s in &v {
+            if s.is_empty() || *s == "." || *s == ".." {
+                return Err(anyhow!(
+                    "Invalid repo name: segment {:?} must be a non-empty directory name",
+                    s
+                ));
+            }
+
+            if s.contains('\\') || s.chars().any(|c| c.is_control()) {
+                return Err(anyhow!(
+                    "Invalid repo name: segment {:?} contains a backslash or control character",
+                    s
+                ));
+            }
+        }
+
         self.url = format!("git@{}:{}/{}.git", v[0], v[1], v[2]);
         Ok(self)
     }
 
+    /// fill sets the fields a config may omit: the name from the key the repo
+    /// is stored under and the url from the name.
+    pub fn fill(&mut self, key: &str) -> Result<&mut Self> {
+        if self.name.is_empty() {
+            self.name = key.to_owned();
+        }
+        if self.url.is_empty() {
+            self.url(self.name.clone())?;
+        }
+        Ok(self)
+    }
+
     pub fn get_url(&self) -> &str {
         self.url.as_str()
     }
@@ -61,17 +128,142 @@ impl Repo {
         self
     }
 
+    pub fn get_pin(&self) -> bool {
+        self.pin
+    }
+
     pub fn sha(&mut self, sha: String) -> &mut Self {
         self.sha = sha;
         self
     }
 
+    pub fn get_sha(&self) -> &str {
+        self.sha.as_str()
+    }
+
+    pub fn branch(&mut self, branch: String) -> &mut Self {
+        self.branch = branch;
+        self
+    }
+
+    pub fn get_branch(&self) -> &str {
+        self.branch.as_str()
+    }
+
+    /// tags sets the repo's tags, sorted and without duplicates.
+    pub fn tags(&mut self, tags: Vec<String>) -> Result<&mut Self> {
+        for t in &tags {
+            valid_tag(t)?;
+        }
+
+        self.tags = tags;
+        self.tags.sort();
+        self.tags.dedup();
+        Ok(self)
+    }
+
+    pub fn get_tags(&self) -> &[String] {
+        self.tags.as_slice()
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
     pub fn to_owned(&mut self) -> Self {
         self.clone()
     }
 }
 
+/// name_from_url derives a repo's name, <host>/<org>/<repo>, from a clone url
+/// in the SCP-like SSH form e.g., git@github.com:org/repo.git, or a URL form
+/// e.g., https://github.com/org/repo or ssh://git@github.com:22/org/repo.git.
+pub fn name_from_url(url: &str) -> Result<String> {
+    let (authority, path) = match url.split_once("://") {
+        Some((_, rest)) => rest
+            .split_once('/')
+            .map(|(a, p)| (a.split(':').next().unwrap_or_default(), p)),
+        None => url.split_once(':'),
+    }
+    .ok_or_else(|| anyhow!("Unsupported url {:?}", url))?;
+
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    let name = format!("{}/{}", host.to_lowercase(), path);
+    Repo::new()
+        .name(name.clone())
+        .with_context(|| format!("Can't derive a name from url {:?}", url))?;
+    Ok(name)
+}
+
+// rewrite_url points url, in any of the forms name_from_url reads or a local
+// path, at the repo named to instead of from.
+fn rewrite_url(url: &str, from: &str, to: &str) -> String {
+    let scheme = url.find("://").map(|i| i + 3);
+    let path_start = match scheme {
+        Some(i) => url[i..].find('/').map_or(url.len(), |j| i + j),
+        None => match url.find(':') {
+            Some(i) if !url[..i].contains('/') => i + 1,
+            _ => 0,
+        },
+    };
+    let (authority, path) = url.split_at(path_start);
+
+    let mut out = authority.to_owned();
+    let (from_host, to_host) = (from.split('/').next(), to.split('/').next());
+    let host_start = authority
+        .rfind('@')
+        .map_or(scheme.unwrap_or_default(), |i| i + 1);
+    let host_end = authority[host_start..]
+        .find([':', '/'])
+        .map_or(authority.len(), |i| host_start + i);
+    if let (Some(f), Some(t)) = (from_host, to_host) {
+        if authority[host_start..host_end].eq_ignore_ascii_case(f) {
+            out.replace_range(host_start..host_end, t);
+        }
+    }
+
+    let trimmed = path.trim_end_matches('/');
+    let slash = &path[trimmed.len()..];
+    let (body, git) = match trimmed.strip_suffix(".git") {
+        Some(b) => (b, ".git"),
+        None => (trimmed, ""),
+    };
+    let org_repo = to.split_once('/').map(|(_, p)| p).unwrap_or_default();
+    let prefix = match body.rsplitn(3, '/').nth(2) {
+        Some(p) => format!("{}/", p),
+        None if body.starts_with('/') => "/".to_owned(),
+        None => String::new(),
+    };
+    out.push_str(&format!("{}{}{}{}", prefix, org_repo, git, slash));
+    out
+}
+
+/// valid_tag checks that a tag can be written on the command line and in a
+/// comma-separated list.
+pub fn valid_tag(tag: &str) -> Result<()> {
+    if tag.is_empty()
+        || tag.contains(',')
+        || tag.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(anyhow!(
+            "Invalid tag {:?}: tags must be non-empty and without commas or whitespace",
+            tag
+        ));
+    }
+    Ok(())
+}
+
+impl Default for Repo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     extern crate log;
@@ -107,6 +299,26 @@ mod tests {
         assert_eq!(got.is_err(), true);
     }
 
+    #[test]
+    fn test_url_path_traversal() {
+        let mut r = setup();
+
+        for name in [
+            "../../etc",
+            "github.com/../..",
+            "github.com/./a",
+            "github.com//a",
+            "/github.com/a",
+            "github.com/a/",
+            "github.com/a\\..\\../a",
+            "github.com/a/a\n",
+            "github.com/a/\0a",
+        ] {
+            let got = r.url(name.to_string());
+            assert!(got.is_err(), "expected {:?} to be rejected", name);
+        }
+    }
+
     #[test]
     fn test_url_valid_name() {
         let mut r = setup();
@@ -116,4 +328,129 @@ mod tests {
             .expect("failed to set url");
         assert_eq!(got.get_url(), "git@github.com:a/a.git");
     }
+
+    #[test]
+    fn test_tags() {
+        let mut r = setup();
+
+        let got = r
+            .tags(vec![
+                "oncall".to_string(),
+                "a".to_string(),
+                "oncall".to_string(),
+            ])
+            .expect("failed to set tags");
+        assert_eq!(got.get_tags(), ["a", "oncall"]);
+        assert!(got.has_tag("oncall"));
+        assert!(!got.has_tag("b"));
+
+        for t in ["", "a,b", "a b", "a\n"] {
+            assert!(
+                r.tags(vec![t.to_string()]).is_err(),
+                "expected {:?} to be rejected",
+                t
+            );
+        }
+    }
+
+    #[test]
+    fn test_name_from_url() {
+        for url in [
+            "git@github.com:mccurdyc/gitrs.git",
+            "git@github.com:mccurdyc/gitrs",
+            "https://github.com/mccurdyc/gitrs.git",
+            "https://user@GitHub.com/mccurdyc/gitrs/",
+            "ssh://git@github.com:22/mccurdyc/gitrs.git",
+        ] {
+            assert_eq!(
+                name_from_url(url).expect("failed to derive name"),
+                "github.com/mccurdyc/gitrs",
+                "{}",
+                url
+            );
+        }
+
+        for url in [
+            "/srv/git/gitrs.git",
+            "https://gitlab.com/group/subgroup/repo.git",
+            "git@github.com:../../etc",
+        ] {
+            assert!(
+                name_from_url(url).is_err(),
+                "expected {:?} to be rejected",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn test_rename() {
+        let rename = |url: &str, to: &str| {
+            let mut r = Repo::new()
+                .name("github.com/old/a".to_string())
+                .unwrap()
+                .to_owned();
+            if !url.is_empty() {
+                r.url = url.to_owned();
+            }
+            r.rename(to.to_string()).expect("rename failed");
+            r.get_url().to_owned()
+        };
+
+        for (url, to, want) in [
+            ("", "github.com/new/b", "git@github.com:new/b.git"),
+            ("", "gitlab.com/new/b", "git@gitlab.com:new/b.git"),
+            (
+                "https://github.com/old/a",
+                "github.com/new/b",
+                "https://github.com/new/b",
+            ),
+            (
+                "https://user@github.com/old/a.git/",
+                "gitlab.com/new/b",
+                "https://user@gitlab.com/new/b.git/",
+            ),
+            (
+                "ssh://git@github.com:2222/old/a.git",
+                "github.com/new/b",
+                "ssh://git@github.com:2222/new/b.git",
+            ),
+            (
+                "git@work-github:old/a.git",
+                "github.com/new/b",
+                "git@work-github:new/b.git",
+            ),
+            (
+                "git@github.com:/old/a.git",
+                "github.com/new/b",
+                "git@github.com:/new/b.git",
+            ),
+            (
+                "https://git.example.com/scm/old/a.git",
+                "github.com/new/b",
+                "https://git.example.com/scm/new/b.git",
+            ),
+            (
+                "/srv/git/old/a.git",
+                "github.com/new/b",
+                "/srv/git/new/b.git",
+            ),
+        ] {
+            assert_eq!(rename(url, to), want, "{:?}", url);
+        }
+
+        let mut r = setup();
+        assert!(r.rename("github.com/new".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_host_org() {
+        let mut r = setup();
+
+        let got = r
+            .name("github.com/mccurdyc/gitrs".to_string())
+            .expect("failed to set name");
+        assert_eq!(got.get_host(), "github.com");
+        assert_eq!(got.get_org(), "mccurdyc");
+    }
 }
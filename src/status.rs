@@ -0,0 +1,408 @@
+use crate::fs;
+use crate::list::Filter;
+use crate::repo::Repo;
+use anyhow::{anyhow, Result};
+use git2::{Branch, BranchType, Oid, Repository, StatusOptions};
+use log::debug;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// Status is the result of the `status` command.
+#[derive(Serialize, Debug)]
+pub struct Status {
+    repos: Vec<RepoStatus>,
+    /// undeclared are directories under the root that the next sync will remove.
+    undeclared: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+pub struct RepoStatus {
+    name: String,
+    present: bool,
+    /// branch is the checked out branch. None when HEAD is detached or unborn.
+    branch: Option<String>,
+    detached: bool,
+    dirty: bool,
+    /// ahead and behind are relative to the branch's upstream as of the last
+    /// fetch. None when there is no upstream.
+    ahead: Option<usize>,
+    behind: Option<usize>,
+    head: Option<String>,
+    /// pin_drift is true when the repo is pinned and HEAD isn't the pinned sha,
+    /// including when the pin has no sha to compare HEAD to.
+    pin_drift: bool,
+    /// origin_mismatch is the URL of the origin remote when it differs from
+    /// the config.
+    origin_mismatch: Option<String>,
+    error: Option<String>,
+}
+
+impl Status {
+    pub fn repos(&self) -> &Vec<RepoStatus> {
+        &self.repos
+    }
+
+    pub fn undeclared(&self) -> &Vec<String> {
+        &self.undeclared
+    }
+}
+
+impl fmt::Display for RepoStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(e) = &self.error {
+            return write!(f, "{}\terror: {}", self.name, e);
+        }
+
+        if !self.present {
+            return write!(f, "{}\tmissing", self.name);
+        }
+
+        let mut s = vec![match (&self.branch, self.detached) {
+            (Some(b), _) => b.to_owned(),
+            (None, true) => "detached".to_owned(),
+            (None, false) => "unborn".to_owned(),
+        }];
+
+        s.push(match self.dirty {
+            true => "dirty".to_owned(),
+            false => "clean".to_owned(),
+        });
+
+        if let (Some(a), Some(b)) = (self.ahead, self.behind) {
+            if a > 0 {
+                s.push(format!("ahead {}", a));
+            }
+            if b > 0 {
+                s.push(format!("behind {}", b));
+            }
+        }
+
+        if self.pin_drift {
+            s.push("pin drift".to_owned());
+        }
+
+        if let Some(o) = &self.origin_mismatch {
+            s.push(format!("origin mismatch ({})", o));
+        }
+
+        write!(f, "{}\t{}", self.name, s.join(", "))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in &self.repos {
+            writeln!(f, "{}", r)?;
+        }
+
+        if !self.undeclared.is_empty() {
+            writeln!(f, "\nUndeclared (removed on next sync):")?;
+            for u in &self.undeclared {
+                writeln!(f, "{}", u)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// status reports the state on disk of every repo in the config that matches
+/// the filter, sorted by name, and the undeclared directories under root.
+///
+/// Undeclared directories are only reported when the filter doesn't select by
+/// tag, since they can't have tags and a selective sync never removes them.
+pub fn status(root: &Path, repos: &HashMap<String, Repo>, filter: &Filter) -> Result<Status> {
+    let mut names: Vec<&String> = repos
+        .iter()
+        .filter(|(_, r)| filter.matches(root, r))
+        .map(|(n, _)| n)
+        .collect();
+    names.sort();
+
+    Ok(Status {
+        repos: names
+            .into_iter()
+            .map(|n| repo_status(root, &repos[n]))
+            .collect(),
+        undeclared: match filter.selective() {
+            true => Vec::new(),
+            false => fs::undeclared(root, repos)?,
+        },
+    })
+}
+
+fn repo_status(root: &Path, r: &Repo) -> RepoStatus {
+    let mut s = RepoStatus {
+        name: r.get_name().to_owned(),
+        ..Default::default()
+    };
+
+    let p = root.join(r.get_name());
+    if !p.exists() {
+        return s;
+    }
+    s.present = true;
+
+    if let Err(e) = inspect(&p, r, &mut s) {
+        debug!("Failed to inspect {:?}: {:#}", p, e);
+        s.error = Some(format!("{:#}", e));
+    }
+
+    s
+}
+
+fn inspect(p: &Path, r: &Repo, s: &mut RepoStatus) -> Result<()> {
+    let repo = Repository::open(p)?;
+
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false);
+    s.dirty = !repo.statuses(Some(&mut opts))?.is_empty();
+
+    if let Ok(remote) = repo.find_remote("origin") {
+        let url = remote.url().unwrap_or_default();
+        if url != r.get_url() {
+            s.origin_mismatch = Some(url.to_owned());
+        }
+    }
+
+    // An unborn branch i.e., an empty repo, has no HEAD to inspect.
+    let head = match repo.head() {
+        Ok(h) => h,
+        Err(_) => return Ok(()),
+    };
+
+    let oid = head
+        .target()
+        .ok_or_else(|| anyhow!("HEAD isn't a direct reference"))?;
+    s.head = Some(oid.to_string());
+    s.detached = repo.head_detached()?;
+
+    // An empty sha would be a prefix of every HEAD, hiding that the pin
+    // doesn't pin anything.
+    if r.get_pin() && (r.get_sha().is_empty() || !oid.to_string().starts_with(r.get_sha())) {
+        s.pin_drift = true;
+    }
+
+    if !head.is_branch() {
+        return Ok(());
+    }
+
+    let branch = Branch::wrap(head);
+    s.branch = branch.name()?.map(|b| b.to_owned());
+
+    if let Ok(upstream) = branch.upstream() {
+        if let Some(u) = upstream.get().target() {
+            let (ahead, behind) = repo.graph_ahead_behind(oid, u)?;
+            s.ahead = Some(ahead);
+            s.behind = Some(behind);
+        }
+    }
+
+    Ok(())
+}
+
+/// unsaved describes the work in the repo at p that deleting it would lose:
+/// uncommitted changes, stashes, and commits that aren't on any remote
+/// branch as of the last fetch. Empty when it's safe to delete.
+pub fn unsaved(p: &Path) -> Result<Vec<String>> {
+    let mut repo = Repository::open(p)?;
+    let mut found = Vec::new();
+
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false);
+    if !repo.statuses(Some(&mut opts))?.is_empty() {
+        found.push("uncommitted changes".to_owned());
+    }
+
+    let mut stashes = 0;
+    repo.stash_foreach(|_, _, _| {
+        stashes += 1;
+        true
+    })?;
+    if stashes > 0 {
+        found.push(format!("{} stashes", stashes));
+    }
+
+    let mut remote: Vec<Oid> = Vec::new();
+    for b in repo.branches(Some(BranchType::Remote))? {
+        if let Some(oid) = b?.0.get().target() {
+            remote.push(oid);
+        }
+    }
+    let pushed = |oid: Oid| -> Result<bool> {
+        for r in &remote {
+            if *r == oid || repo.graph_descendant_of(*r, oid)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+
+    for b in repo.branches(Some(BranchType::Local))? {
+        let b = b?.0;
+        if let Some(oid) = b.get().target() {
+            if !pushed(oid)? {
+                let name = b.name()?.unwrap_or_default();
+                found.push(format!("unpushed commits on {}", name));
+            }
+        }
+    }
+    if repo.head_detached()? {
+        if let Some(oid) = repo.head()?.target() {
+            if !pushed(oid)? {
+                found.push("unpushed commits on a detached HEAD".to_owned());
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+    use std::fs::{create_dir_all, write};
+    use tempfile::{tempdir, TempDir};
+
+    fn setup() -> TempDir {
+        tempdir().expect("Failed to create tempdir")
+    }
+
+    fn test_repo(name: &str, pin: bool, sha: &str) -> Repo {
+        Repo::new()
+            .name(name.to_string())
+            .expect("name failed")
+            .pin(pin)
+            .sha(sha.to_string())
+            .to_owned()
+    }
+
+    // init creates a git repository with a single commit and an origin remote.
+    fn init(root: &Path, name: &str, origin: &str) -> Repository {
+        let p = root.join(name);
+        create_dir_all(&p).expect("failed to create dir");
+
+        let repo = Repository::init(&p).expect("failed to init repo");
+        repo.remote("origin", origin).expect("failed to add remote");
+
+        {
+            let sig = Signature::now("test", "test@example.com").expect("signature failed");
+            let tree_id = repo.index().unwrap().write_tree().unwrap();
+            let tree = repo.find_tree(tree_id).unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+                .expect("failed to commit");
+        }
+
+        repo
+    }
+
+    #[test]
+    fn test_status() {
+        let root = setup();
+        let p = root.path();
+
+        let a = test_repo("github.com/a/a", false, "");
+        let b = test_repo("github.com/b/b", false, "");
+        let c = test_repo("github.com/c/c", true, "0000000");
+        let repos = HashMap::from([
+            (a.get_name().to_string(), a.clone()),
+            (b.get_name().to_string(), b.clone()),
+            (c.get_name().to_string(), c.clone()),
+        ]);
+
+        init(p, "github.com/a/a", a.get_url());
+        write(p.join("github.com/a/a/new.txt"), "new").expect("failed to write");
+        init(p, "github.com/c/c", "git@github.com:other/c.git");
+        create_dir_all(p.join("github.com/old/old")).expect("failed to create dir");
+
+        let got = status(p, &repos, &Filter::new()).expect("status failed");
+
+        let a = &got.repos()[0];
+        assert!(a.present);
+        assert!(a.dirty);
+        assert!(!a.detached);
+        assert!(a.branch.is_some());
+        assert_eq!(a.origin_mismatch, None);
+        assert_eq!(a.ahead, None);
+
+        let b = &got.repos()[1];
+        assert!(!b.present);
+        assert_eq!(b.to_string(), "github.com/b/b\tmissing");
+
+        let c = &got.repos()[2];
+        assert!(!c.dirty);
+        assert!(c.pin_drift);
+        assert_eq!(
+            c.origin_mismatch,
+            Some("git@github.com:other/c.git".to_string())
+        );
+
+        assert_eq!(got.undeclared(), &vec!["github.com/old/old".to_string()]);
+
+        let got = status(
+            p,
+            &repos,
+            Filter::new().exclude_tags(vec!["none".to_string()]),
+        )
+        .expect("status failed");
+        assert_eq!(got.repos().len(), 3);
+        assert!(got.undeclared().is_empty());
+    }
+
+    #[test]
+    fn test_status_detached() {
+        let root = setup();
+        let p = root.path();
+
+        let a = test_repo("github.com/a/a", false, "");
+        let repos = HashMap::from([(a.get_name().to_string(), a.clone())]);
+
+        let repo = init(p, "github.com/a/a", a.get_url());
+        let oid = repo.head().unwrap().target().unwrap();
+        repo.set_head_detached(oid).expect("failed to detach");
+
+        let got = status(p, &repos, &Filter::new()).expect("status failed");
+        assert!(got.repos()[0].detached);
+        assert_eq!(got.repos()[0].branch, None);
+        assert_eq!(got.repos()[0].head, Some(oid.to_string()));
+        assert!(!got.repos()[0].pin_drift);
+
+        // A pin without a sha is reported rather than matching any HEAD.
+        let a = test_repo("github.com/a/a", true, "");
+        let repos = HashMap::from([(a.get_name().to_string(), a.clone())]);
+        let got = status(p, &repos, &Filter::new()).expect("status failed");
+        assert!(got.repos()[0].pin_drift);
+
+        let a = test_repo("github.com/a/a", true, &oid.to_string()[..7]);
+        let repos = HashMap::from([(a.get_name().to_string(), a.clone())]);
+        let got = status(p, &repos, &Filter::new()).expect("status failed");
+        assert!(!got.repos()[0].pin_drift);
+    }
+
+    #[test]
+    fn test_unsaved() {
+        let root = setup();
+        let p = root.path();
+        let repo = init(p, "github.com/a/a", "git@github.com:a/a.git");
+        let dir = p.join("github.com/a/a");
+
+        // The only commit isn't on a remote branch.
+        let got = unsaved(&dir).expect("unsaved failed");
+        assert_eq!(got.len(), 1);
+        assert!(got[0].starts_with("unpushed commits on "));
+
+        let oid = repo.head().unwrap().target().unwrap();
+        repo.reference("refs/remotes/origin/main", oid, false, "fetch")
+            .expect("failed to create remote branch");
+        assert!(unsaved(&dir).expect("unsaved failed").is_empty());
+
+        write(dir.join("new.txt"), "new").expect("failed to write");
+        assert_eq!(
+            unsaved(&dir).expect("unsaved failed"),
+            vec!["uncommitted changes"]
+        );
+    }
+}
@@ -0,0 +1,644 @@
+use crate::fs;
+use crate::manifest;
+use crate::migrate;
+use crate::repo::{self, Repo};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+const TOP_LEVEL_FIELDS: &[&str] = &["metadata", "include", "exclude", "repos"];
+const METADATA_FIELDS: &[&str] = &["version", "root", "last_sync", "repos_format", "history"];
+const REPO_FIELDS: &[&str] = &["name", "url", "pin", "sha", "branch", "tags"];
+
+/// Validation is every problem found in a config file.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Validation {
+    path: PathBuf,
+    problems: Vec<Problem>,
+}
+
+/// Problem is a single problem in a config file. line and column are 1-based
+/// and point at the offending key, when it could be found.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Problem {
+    line: Option<usize>,
+    column: Option<usize>,
+    message: String,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.problems.is_empty() {
+            return write!(f, "{} is valid", self.path.display());
+        }
+
+        for p in &self.problems {
+            write!(f, "{}", self.path.display())?;
+            if let (Some(l), Some(c)) = (p.line, p.column) {
+                write!(f, ":{}:{}", l, c)?;
+            }
+            writeln!(f, ": {}", p.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// validate checks the config file at p, which root is the active root for,
+/// and reports every problem found rather than stopping at the first.
+pub fn validate(p: &Path, root: &Path) -> Result<Validation> {
+    let text = std::fs::read_to_string(p).with_context(|| format!("failed to read {:?}", p))?;
+    let mut c = Checker {
+        lines: text.lines().map(line).collect(),
+        problems: Vec::new(),
+    };
+
+    match serde_yaml::from_str::<Value>(&text) {
+        Ok(v) => {
+            c.config(&v, root);
+            c.includes(&v, p);
+        }
+        Err(e) => c.problems.push(Problem {
+            line: e.location().map(|l| l.line()),
+            column: e.location().map(|l| l.column()),
+            message: format!("invalid YAML: {}", e),
+        }),
+    }
+
+    // Problems are found in the order they're checked, not the file's.
+    c.problems.sort_by_key(|p| p.line);
+    Ok(Validation {
+        path: p.to_path_buf(),
+        problems: c.problems,
+    })
+}
+
+struct Checker<'a> {
+    lines: Vec<Option<Line<'a>>>,
+    problems: Vec<Problem>,
+}
+
+// Seg is a segment of the path to a node in the config.
+#[derive(Debug, Clone, Copy)]
+enum Seg<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+impl Checker<'_> {
+    fn config(&mut self, v: &Value, root: &Path) {
+        let m = match v.as_mapping() {
+            Some(m) => m,
+            None => return self.problem(&[], "config should be a mapping".to_owned()),
+        };
+        self.unknown(m, &[], TOP_LEVEL_FIELDS);
+
+        match m.get("metadata") {
+            Some(md) => self.metadata(md, root),
+            None => self.problem(&[], "missing field `metadata`".to_owned()),
+        }
+
+        match m.get("repos") {
+            None | Some(Value::Null) => {}
+            Some(Value::Mapping(repos)) => {
+                for (k, r) in repos {
+                    match k.as_str() {
+                        Some(k) => self.repo(r, &[Seg::Key("repos"), Seg::Key(k)], Some(k)),
+                        None => self.problem(
+                            &[Seg::Key("repos")],
+                            format!("repo key {:?} should be a string", k),
+                        ),
+                    }
+                }
+            }
+            Some(Value::Sequence(repos)) => {
+                let mut seen = HashMap::new();
+                for (i, r) in repos.iter().enumerate() {
+                    let at = [Seg::Key("repos"), Seg::Index(i)];
+                    self.repo(r, &at, None);
+
+                    if let Some(n) = r.get("name").and_then(|n| n.as_str()) {
+                        if let Some(first) = seen.insert(n, i) {
+                            self.problem(
+                                &at,
+                                format!("duplicate repo {:?}, first listed at repos[{}]", n, first),
+                            );
+                        }
+                    }
+                }
+            }
+            Some(_) => self.problem(
+                &[Seg::Key("repos")],
+                "repos should be a mapping or a list".to_owned(),
+            ),
+        }
+    }
+
+    // includes checks the include and exclude lists and that the included
+    // manifests can be merged.
+    fn includes(&mut self, v: &Value, p: &Path) {
+        for key in ["include", "exclude"] {
+            match v.get(key) {
+                None | Some(Value::Null) => {}
+                Some(Value::Sequence(items)) => {
+                    for (i, item) in items.iter().enumerate() {
+                        let at = [Seg::Key(key), Seg::Index(i)];
+                        match (key, item) {
+                            ("exclude", Value::String(s)) => {
+                                if let Err(e) = glob::Pattern::new(s) {
+                                    self.problem(&at, format!("invalid glob {:?}: {}", s, e));
+                                }
+                            }
+                            ("include", Value::String(_)) => {}
+                            ("include", Value::Mapping(_)) => {
+                                let r = serde_yaml::from_value::<manifest::Remote>(item.clone());
+                                if let Err(e) = r {
+                                    self.problem(&at, format!("invalid git include: {}", e));
+                                }
+                            }
+                            ("include", _) => self.problem(
+                                &at,
+                                "include should be a path or a {git, path, ref} mapping".to_owned(),
+                            ),
+                            _ => self.problem(&at, format!("{} should be a string", key)),
+                        }
+                    }
+                }
+                Some(_) => self.problem(&[Seg::Key(key)], format!("{} should be a list", key)),
+            }
+        }
+
+        if !self.problems.is_empty() {
+            return;
+        }
+        match manifest::inherited(v, p) {
+            Ok(layer) => {
+                for (name, e) in layer.entries() {
+                    if let Err(err) = Repo::new().name(name.to_owned()) {
+                        self.problem(
+                            &[Seg::Key("include")],
+                            format!("invalid repo {:?} in {:?}: {}", name, e.source, err),
+                        );
+                    }
+                }
+            }
+            Err(e) => self.problem(&[Seg::Key("include")], format!("{:#}", e)),
+        }
+    }
+
+    fn metadata(&mut self, v: &Value, root: &Path) {
+        let at = [Seg::Key("metadata")];
+        let m = match v.as_mapping() {
+            Some(m) => m,
+            None => return self.problem(&at, "metadata should be a mapping".to_owned()),
+        };
+        self.unknown(m, &at, METADATA_FIELDS);
+
+        let mut config = Value::Mapping(Mapping::new());
+        config["metadata"] = v.clone();
+        if let Err(e) = migrate::needed(&config) {
+            self.problem(&[Seg::Key("metadata"), Seg::Key("version")], e.to_string());
+        }
+
+        match m.get("root").map(|r| r.as_str()) {
+            None => self.problem(&at, "missing field `root`".to_owned()),
+            Some(None) => self.problem(
+                &[Seg::Key("metadata"), Seg::Key("root")],
+                "root should be a string".to_owned(),
+            ),
+            Some(Some(r)) => match fs::expand(Path::new(r)) {
+                Ok(e) if !same_path(&e, root) => self.problem(
+                    &[Seg::Key("metadata"), Seg::Key("root")],
+                    format!("root {:?} isn't the active root {:?}", r, root),
+                ),
+                Ok(_) => {}
+                Err(e) => self.problem(
+                    &[Seg::Key("metadata"), Seg::Key("root")],
+                    format!("root {:?} can't be expanded: {}", r, e),
+                ),
+            },
+        }
+
+        if let Some(l) = m.get("last_sync") {
+            if !l.is_string() {
+                self.problem(
+                    &[Seg::Key("metadata"), Seg::Key("last_sync")],
+                    "last_sync should be a string".to_owned(),
+                );
+            }
+        }
+
+        if let Some(h) = m.get("history") {
+            if !h.is_bool() {
+                self.problem(
+                    &[Seg::Key("metadata"), Seg::Key("history")],
+                    "history should be a boolean".to_owned(),
+                );
+            }
+        }
+
+        if let Some(f) = m.get("repos_format") {
+            if !matches!(f.as_str(), Some("map") | Some("list")) {
+                self.problem(
+                    &[Seg::Key("metadata"), Seg::Key("repos_format")],
+                    format!("repos_format should be `map` or `list`, not {:?}", f),
+                );
+            }
+        }
+    }
+
+    // repo checks a single repo, where key is its key when repos is a mapping.
+    fn repo(&mut self, v: &Value, at: &[Seg], key: Option<&str>) {
+        let field = |f| [at, &[Seg::Key(f)]].concat();
+
+        let m = match v {
+            Value::Mapping(m) => m,
+            _ => return self.problem(at, "repo should be a mapping".to_owned()),
+        };
+        self.unknown(m, at, REPO_FIELDS);
+
+        if let Some(k) = key {
+            self.name(k, at);
+        }
+
+        let name = match (m.get("name"), key) {
+            (Some(Value::String(n)), Some(k)) if n != k => {
+                self.problem(
+                    &field("name"),
+                    format!("name {:?} doesn't match its key {:?}", n, k),
+                );
+                Some(n.as_str())
+            }
+            (Some(Value::String(n)), k) => {
+                if k.is_none() {
+                    self.name(n, &field("name"));
+                }
+                Some(n.as_str())
+            }
+            (Some(n), _) => {
+                self.problem(&field("name"), format!("name {:?} should be a string", n));
+                None
+            }
+            (None, Some(k)) => Some(k),
+            (None, None) => {
+                self.problem(at, "missing field `name`".to_owned());
+                None
+            }
+        };
+        // A mismatched name is checked too, since it's also used as a path.
+        if let (Some(n), Some(k)) = (name, key) {
+            if n != k {
+                self.name(n, &field("name"));
+            }
+        }
+
+        // A url in another form is fine as long as it's for the same repo.
+        match (m.get("url"), name.and_then(url)) {
+            (Some(Value::String(u)), Some(want))
+                if *u != want && repo::name_from_url(u).ok().as_deref() != name =>
+            {
+                self.problem(
+                    &field("url"),
+                    format!(
+                        "url {:?} doesn't match name {:?}, which is {:?}",
+                        u,
+                        name.unwrap_or_default(),
+                        want
+                    ),
+                )
+            }
+            (Some(u), _) if !u.is_string() => {
+                self.problem(&field("url"), format!("url {:?} should be a string", u))
+            }
+            _ => {}
+        }
+
+        let pin = match m.get("pin") {
+            None => false,
+            Some(Value::Bool(b)) => *b,
+            Some(p) => {
+                self.problem(
+                    &field("pin"),
+                    format!("pin {:?} should be true or false", p),
+                );
+                false
+            }
+        };
+
+        match m.get("sha") {
+            None | Some(Value::Null) if pin => {
+                self.problem(at, "pinned repo doesn't have a sha".to_owned())
+            }
+            Some(Value::String(s)) if s.is_empty() && pin => {
+                self.problem(&field("sha"), "pinned repo has an empty sha".to_owned())
+            }
+            Some(Value::String(s)) if !s.is_empty() && !is_sha(s) => self.problem(
+                &field("sha"),
+                format!("sha {:?} should be 7 to 40, or 64, hex characters", s),
+            ),
+            Some(s) if !s.is_string() && !s.is_null() => {
+                self.problem(&field("sha"), format!("sha {:?} should be a string", s))
+            }
+            _ => {}
+        }
+
+        if let Some(b) = m.get("branch") {
+            if !b.is_string() {
+                self.problem(
+                    &field("branch"),
+                    format!("branch {:?} should be a string", b),
+                );
+            }
+        }
+
+        match m.get("tags") {
+            None | Some(Value::Null) => {}
+            Some(Value::Sequence(tags)) => {
+                for (i, t) in tags.iter().enumerate() {
+                    let at = [field("tags").as_slice(), &[Seg::Index(i)]].concat();
+                    match t.as_str().map(repo::valid_tag) {
+                        Some(Ok(())) => {}
+                        Some(Err(e)) => self.problem(&at, e.to_string()),
+                        None => self.problem(&at, format!("tag {:?} should be a string", t)),
+                    }
+                }
+            }
+            Some(t) => self.problem(&field("tags"), format!("tags {:?} should be a list", t)),
+        }
+    }
+
+    fn name(&mut self, n: &str, at: &[Seg]) {
+        if let Err(e) = Repo::new().name(n.to_owned()) {
+            self.problem(at, format!("invalid repo {:?}: {}", n, e));
+        }
+    }
+
+    fn unknown(&mut self, m: &Mapping, at: &[Seg], known: &[&str]) {
+        for k in m.keys() {
+            match k.as_str() {
+                Some(s) if known.contains(&s) => {}
+                Some(s) => self.problem(
+                    &[at, &[Seg::Key(s)]].concat(),
+                    format!("unknown field `{}`, expected one of {:?}", s, known),
+                ),
+                None => self.problem(at, format!("unknown field {:?}", k)),
+            }
+        }
+    }
+
+    fn problem(&mut self, at: &[Seg], message: String) {
+        let loc = self.locate(at);
+        self.problems.push(Problem {
+            line: loc.map(|l| l.0),
+            column: loc.map(|l| l.1),
+            message,
+        });
+    }
+
+    // locate finds the 1-based line and column of the node at path in the
+    // config's text. Only block style is followed; when a segment can't be
+    // found e.g., it's in a flow mapping, the closest parent is returned.
+    fn locate(&self, path: &[Seg]) -> Option<(usize, usize)> {
+        let content = |i: usize| self.lines[i].as_ref();
+        let mut range = 0..self.lines.len();
+        let mut found = None;
+
+        for seg in path {
+            let (i, col) = match seg {
+                Seg::Key(k) => {
+                    let level = range.clone().find_map(|i| content(i).map(|l| l.col))?;
+                    let i = range.clone().find(|i| {
+                        content(*i).is_some_and(|l| l.col == level && l.key() == Some(k))
+                    });
+                    match i {
+                        Some(i) => (i, level),
+                        None => break,
+                    }
+                }
+                Seg::Index(n) => {
+                    let level = range.clone().find_map(|i| content(i).map(|l| l.indent))?;
+                    let i = range
+                        .clone()
+                        .filter(|i| content(*i).is_some_and(|l| l.item && l.indent == level))
+                        .nth(*n);
+                    match i {
+                        Some(i) => (i, level),
+                        None => break,
+                    }
+                }
+            };
+            found = Some((i + 1, col + 1));
+
+            // Narrow the range to the node's children. A list item's own line
+            // holds its first key, and a key's children may be list items at
+            // the key's indentation.
+            let end = (i + 1..range.end)
+                .find(|j| match (content(*j), seg) {
+                    (Some(l), Seg::Key(_)) => l.indent < col || (l.indent == col && !l.item),
+                    (Some(l), Seg::Index(_)) => l.indent <= col,
+                    (None, _) => false,
+                })
+                .unwrap_or(range.end);
+            range = match seg {
+                Seg::Key(_) => i + 1..end,
+                Seg::Index(_) => i..end,
+            };
+        }
+
+        found
+    }
+}
+
+// Line is a non-blank, non-comment line of the config's text.
+struct Line<'a> {
+    // indent is the column of the first character.
+    indent: usize,
+    // item is true when the line starts a list item.
+    item: bool,
+    // col is the column of content, which is after the `- ` of a list item.
+    col: usize,
+    content: &'a str,
+}
+
+impl Line<'_> {
+    fn key(&self) -> Option<&str> {
+        self.content
+            .split_once(':')
+            .map(|(k, _)| k.trim().trim_matches(|c| c == '\'' || c == '"'))
+    }
+}
+
+fn line(l: &str) -> Option<Line<'_>> {
+    let rest = l.trim_start();
+    if rest.is_empty() || rest.starts_with('#') {
+        return None;
+    }
+
+    let indent = l.len() - rest.len();
+    match rest.strip_prefix('-') {
+        Some(after) if after.is_empty() || after.starts_with(' ') => {
+            let content = after.trim_start();
+            Some(Line {
+                indent,
+                item: true,
+                col: l.len() - content.len(),
+                content,
+            })
+        }
+        _ => Some(Line {
+            indent,
+            item: false,
+            col: indent,
+            content: rest,
+        }),
+    }
+}
+
+fn url(name: &str) -> Option<String> {
+    Repo::new()
+        .name(name.to_owned())
+        .ok()
+        .map(|r| r.get_url().to_owned())
+}
+
+fn is_sha(s: &str) -> bool {
+    ((7..=40).contains(&s.len()) || s.len() == 64) && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn check(text: &str) -> Vec<(Option<usize>, Option<usize>, String)> {
+        let root = tempdir().expect("Failed to create tempdir");
+        let p = root.path().join("test.yaml");
+        std::fs::write(
+            &p,
+            text.replace("$ROOT", &root.path().display().to_string()),
+        )
+        .expect("failed to write config");
+
+        validate(&p, root.path())
+            .expect("validate failed")
+            .problems
+            .into_iter()
+            .map(|p| (p.line, p.column, p.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let got = check(
+            "metadata:\n  version: v1beta\n  root: $ROOT\nrepos:\n  github.com/a/a:\n    name: github.com/a/a\n    url: git@github.com:a/a.git\n    pin: true\n    sha: 0123abc\n",
+        );
+        assert_eq!(got, vec![]);
+
+        let got = check("metadata:\n  version: v1beta\n  root: $ROOT\nrepos:\n- name: github.com/a/a\n- name: github.com/b/b\n");
+        assert_eq!(got, vec![]);
+
+        let got = check("metadata:\n  version: v1beta\n  root: $ROOT\nrepos:\n  github.com/a/a:\n    url: https://github.com/a/a\n  github.com/b/b:\n    url: ssh://git@github.com:2222/b/b.git\n");
+        assert_eq!(got, vec![]);
+    }
+
+    #[test]
+    fn test_invalid_yaml() {
+        let got = check("metadata:\n  version: v1beta\n  root: [\n");
+        assert_eq!(got.len(), 1);
+        assert!(got[0].0.is_some());
+        assert!(got[0].2.starts_with("invalid YAML"));
+    }
+
+    #[test]
+    fn test_every_problem() {
+        let got = check(
+            "\
+metadata:
+  version: v1beta
+  root: /elsewhere
+  colour: blue
+repos:
+  github.com/a/a:
+    name: github.com/a/b
+    url: git@github.com:a/a.git
+  github.com/../a:
+    url: git@github.com:a/a.git
+  github.com/c/c:
+    url: git@gitlab.com:c/c.git
+    pin: true
+    sha: ''
+  github.com/d/d:
+    pin: true
+    sha: xyz
+    depth: 1
+  github.com/e/e:
+    tags:
+    - ok
+    - a b
+",
+        );
+
+        let at = |l: usize, c: usize, m: &str| {
+            assert!(
+                got.iter()
+                    .any(|(gl, gc, gm)| *gl == Some(l) && *gc == Some(c) && gm.contains(m)),
+                "expected {}:{}: {} in {:?}",
+                l,
+                c,
+                m,
+                got
+            )
+        };
+        at(3, 3, "isn't the active root");
+        at(4, 3, "unknown field `colour`");
+        at(7, 5, "doesn't match its key");
+        at(8, 5, "doesn't match name");
+        at(9, 3, "invalid repo");
+        at(12, 5, "doesn't match name");
+        at(14, 5, "empty sha");
+        at(17, 5, "hex characters");
+        at(18, 5, "unknown field `depth`");
+        at(22, 5, "Invalid tag \"a b\"");
+    }
+
+    #[test]
+    fn test_list_duplicate() {
+        let got = check(
+            "metadata:\n  version: v1beta\n  root: $ROOT\nrepos:\n- name: github.com/a/a\n- pin: false\n  name: github.com/a/a\n- pin: true\n",
+        );
+        assert_eq!(
+            got,
+            vec![
+                (
+                    Some(6),
+                    Some(1),
+                    "duplicate repo \"github.com/a/a\", first listed at repos[0]".to_string()
+                ),
+                (Some(8), Some(1), "missing field `name`".to_string()),
+                (
+                    Some(8),
+                    Some(1),
+                    "pinned repo doesn't have a sha".to_string()
+                ),
+            ]
+        );
+    }
+}
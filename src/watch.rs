@@ -0,0 +1,230 @@
+use crate::config::Config;
+use crate::fs;
+use crate::output::{self, Format};
+use crate::repo::Repo;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info};
+use notify::{Event, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// Diff is the difference between two configs that needs applied to disk.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diff<'a> {
+    added: Vec<&'a Repo>,
+    removed: Vec<String>,
+}
+
+impl Diff<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// diff returns the repos in new that aren't in old and the names of the repos
+/// in old that aren't in new, both sorted by name.
+pub fn diff<'a>(old: &HashMap<String, Repo>, new: &'a HashMap<String, Repo>) -> Diff<'a> {
+    let mut added: Vec<&Repo> = new
+        .iter()
+        .filter(|(k, _)| !old.contains_key(*k))
+        .map(|(_, r)| r)
+        .collect();
+    added.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+    let mut removed: Vec<String> = old
+        .keys()
+        .filter(|k| !new.contains_key(*k))
+        .cloned()
+        .collect();
+    removed.sort();
+
+    Diff { added, removed }
+}
+
+/// watch blocks, applying changes to the config file to disk as they happen.
+///
+/// Bursts of writes are collapsed into a single change once the file has been
+/// quiet for the debounce duration. Invalid configs are logged and skipped
+/// so that a half-written file never removes repos.
+pub fn watch(cfg: Config, debounce: Duration, format: Format) -> Result<()> {
+    let path = cfg.path();
+    // Editors commonly replace the file via a rename, which a watch on the file
+    // itself would miss, so watch the directory and filter on the file name.
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("config path has no parent: {:?}", path))?;
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx).context("failed to create watcher")?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("failed to watch {:?}", dir))?;
+
+    info!("Watching config file: {:?}", path);
+
+    let mut current = cfg;
+    loop {
+        if !wait(&rx, &path, debounce)? {
+            return Ok(());
+        }
+
+        let next = match current.read(path.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Ignoring invalid config {:?}: {:#}", path, e);
+                continue;
+            }
+        };
+
+        let d = diff(current.repos(), next.repos());
+        debug!("Config diff: {:?}", d);
+
+        let mut guard = fs::Guard::new();
+        guard.sanity(next.sanity_check().err().map(|e| e.to_string()));
+        if let Err(e) = guard.check(next.root().as_path(), next.repos().len(), &d.removed) {
+            // Keep the current config so that the diff is applied once it's fixed.
+            error!("Ignoring config change: {:#}", e);
+            continue;
+        }
+
+        if !d.is_empty() {
+            let started = Instant::now();
+            match apply(next.root().as_path(), &d) {
+                Ok(Some(report)) => output::emit(format, "watch", started, &report)?,
+                // Keep the current config so that the diff is applied on the
+                // next change instead.
+                Ok(None) => {
+                    info!("Skipping config change: another sync is running");
+                    continue;
+                }
+                Err(e) => {
+                    error!("Skipping config change: {:#}", e);
+                    continue;
+                }
+            }
+        }
+
+        current = next;
+    }
+}
+
+// apply applies d to root under the root's lock, since only one sync runs
+// against a root at a time. Returns None when another process holds it.
+fn apply(root: &Path, d: &Diff) -> Result<Option<fs::SyncReport>> {
+    let _lock = match fs::lock(root)? {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    Ok(Some(fs::apply(root, &d.added, &d.removed)))
+}
+
+// wait blocks until the config file changes and then until it has been quiet
+// for the debounce duration. Returns false when the watcher has gone away.
+fn wait(rx: &Receiver<notify::Result<Event>>, path: &Path, debounce: Duration) -> Result<bool> {
+    loop {
+        match rx.recv() {
+            Ok(ev) => {
+                if relevant(&ev?, path) {
+                    break;
+                }
+            }
+            Err(_) => return Ok(false),
+        }
+    }
+
+    let mut deadline = Instant::now() + debounce;
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(ev) => {
+                if relevant(&ev?, path) {
+                    deadline = Instant::now() + debounce;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => return Ok(true),
+            Err(RecvTimeoutError::Disconnected) => return Ok(false),
+        }
+    }
+}
+
+fn relevant(ev: &Event, path: &Path) -> bool {
+    !ev.kind.is_access() && ev.paths.iter().any(|p| p.file_name() == path.file_name())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, EventKind};
+    use std::path::PathBuf;
+
+    fn test_repos(names: &[&str]) -> HashMap<String, Repo> {
+        names
+            .iter()
+            .map(|n| {
+                (
+                    n.to_string(),
+                    Repo::new()
+                        .name(n.to_string())
+                        .expect("name failed")
+                        .to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = test_repos(&["github.com/a/a", "github.com/b/b"]);
+        let new = test_repos(&["github.com/b/b", "github.com/d/d", "github.com/c/c"]);
+
+        let got = diff(&old, &new);
+        assert_eq!(
+            got.added
+                .iter()
+                .map(|r| r.get_name())
+                .collect::<Vec<&str>>(),
+            vec!["github.com/c/c", "github.com/d/d"]
+        );
+        assert_eq!(got.removed, vec!["github.com/a/a".to_string()]);
+
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_apply_locked() {
+        let root = tempfile::tempdir().expect("Failed to create tempdir");
+        std::fs::create_dir_all(root.path().join("github.com/a/a")).expect("failed to create dir");
+        let old = test_repos(&["github.com/a/a"]);
+        let new = test_repos(&[]);
+        let d = diff(&old, &new);
+
+        // Another sync holds the root, so nothing is removed.
+        let held = fs::lock(root.path()).expect("lock failed");
+        assert!(held.is_some());
+        assert_eq!(apply(root.path(), &d).expect("apply failed"), None);
+        assert!(root.path().join("github.com/a/a").exists());
+
+        drop(held);
+        let report = apply(root.path(), &d).expect("apply failed");
+        assert_eq!(report.expect("expected a report").repos().len(), 1);
+        assert!(!root.path().join("github.com/a/a").exists());
+    }
+
+    #[test]
+    fn test_relevant() {
+        let path = PathBuf::from("/root/.gitrs.yaml");
+
+        let ev = Event::new(EventKind::Create(CreateKind::File))
+            .add_path(PathBuf::from("/root/.gitrs.yaml"));
+        assert!(relevant(&ev, &path));
+
+        let ev = Event::new(EventKind::Create(CreateKind::Folder))
+            .add_path(PathBuf::from("/root/github.com"));
+        assert!(!relevant(&ev, &path));
+
+        let ev = Event::new(EventKind::Access(AccessKind::Any))
+            .add_path(PathBuf::from("/root/.gitrs.yaml"));
+        assert!(!relevant(&ev, &path));
+    }
+}